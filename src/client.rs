@@ -1,66 +1,415 @@
-use std::collections::HashMap;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    io::{BufReader, IsTerminal, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
+use clap::ValueEnum;
 use color_eyre::{
-    eyre::{bail, eyre, Context, OptionExt, Result},
+    eyre::{bail, eyre, Context, OptionExt, Report, Result},
     Section,
 };
+use rand::Rng;
 use reqwest::{
-    blocking::{Client, Response},
-    header::{HeaderMap, HeaderValue},
+    blocking::{
+        multipart::{Form, Part},
+        Client, RequestBuilder, Response,
+    },
+    header::{HeaderMap, HeaderValue, HOST},
+    redirect::Policy,
+    Method, StatusCode,
 };
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
+use url::Url;
+
+use crate::{json_logs, log_diff, log_event};
 
-use crate::log_event;
+/// The account used for provisioning unless another one is given.
+pub const DEFAULT_AUTH_USER: &str = "idm_admin";
 
+pub const ENDPOINT_STATUS: &str = "/status";
 pub const ENDPOINT_AUTH: &str = "/v1/auth";
 pub const ENDPOINT_GROUP: &str = "/v1/group";
 pub const ENDPOINT_PERSON: &str = "/v1/person";
 pub const ENDPOINT_OAUTH2: &str = "/v1/oauth2";
+pub const ENDPOINT_DOMAIN: &str = "/v1/domain";
+pub const ENDPOINT_SERVICE_ACCOUNT: &str = "/v1/service_account";
+pub const ENDPOINT_SYNC_ACCOUNT: &str = "/v1/sync_account";
+pub const ENDPOINT_CREDENTIAL: &str = "/v1/credential";
+pub const ENDPOINT_OAUTH2_IMAGE: &str = "/ui/images/oauth2";
+
+/// The default upper bound for the delay between two readiness checks.
+pub const DEFAULT_WAIT_READY_MAX_INTERVAL: Duration = Duration::from_secs(16);
+/// The default number of consecutive connection failures after which the circuit breaker opens.
+pub const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 10;
+/// The default time to wait once the circuit breaker has opened.
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+/// Printed in place of secret values, which must never appear in any output.
+const REDACTED: &str = "<redacted>";
+/// How long to wait for a newly created entity to become visible.
+pub const CREATED_ENTITY_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait RequestBuilderExt {
+    fn send_authenticated(self, client: &KanidmClient) -> Result<Response>;
+}
+
+impl RequestBuilderExt for RequestBuilder {
+    /// Sends the request with the idm_admin session headers. If the server reports that
+    /// the session has expired, a new session is created and the request is retried once.
+    /// In dry-run mode, mutating requests are only recorded and never sent.
+    fn send_authenticated(self, client: &KanidmClient) -> Result<Response> {
+        let retry_request = self.try_clone();
+        let (http_client, request) = self.headers(client.idm_admin_headers.borrow().clone()).build_split();
+        let request = request?;
+
+        if request.method() != Method::GET {
+            client
+                .changes
+                .borrow_mut()
+                .push(format!("{} {}", request.method(), request.url().path()));
+
+            if client.dry_run {
+                return Ok(http::Response::new("null").into());
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            url = %request.url(),
+            status = tracing::field::Empty
+        )
+        .entered();
+
+        client.throttle();
+        let response = http_client.execute(request)?;
+
+        let response = match retry_request {
+            Some(retry_request) if response.status() == StatusCode::UNAUTHORIZED => {
+                log_event("Reauth", "idm_admin session expired, authenticating again");
+                client.authenticate()?;
+                client.throttle();
+                retry_request
+                    .headers(client.idm_admin_headers.borrow().clone())
+                    .send()?
+            }
+            _ => response,
+        };
+
+        #[cfg(feature = "tracing")]
+        span.record("status", response.status().as_u16());
+
+        Ok(response)
+    }
+}
 
 trait ResponseExt {
-    fn get_json_response(self) -> Result<Value>;
-    fn detailed_error_for_status(self) -> Result<Response>;
+    fn get_json_response(self, client: &KanidmClient) -> Result<Value>;
+    fn get_json_response_streaming<T: DeserializeOwned>(self, client: &KanidmClient) -> Result<T>;
+    fn detailed_error_for_status(self, client: &KanidmClient) -> Result<Response>;
+}
+
+/// Turns the structured errors returned by kanidm into a readable message, e.g.
+/// `{"schemaviolation":{"missingmustattribute":["name"]}}` becomes
+/// `schemaviolation: missingmustattribute: name`.
+fn describe_kanidm_error(value: &Value) -> String {
+    match value {
+        Value::String(x) => x.clone(),
+        Value::Object(map) => {
+            if let Some(message) = map.get("message").or_else(|| map.get("msg")).and_then(|x| x.as_str()) {
+                return message.to_string();
+            }
+
+            map.iter()
+                .map(|(key, value)| match value {
+                    Value::Null => key.clone(),
+                    value => format!("{key}: {}", describe_kanidm_error(value)),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        Value::Array(xs) => xs.iter().map(describe_kanidm_error).collect::<Vec<_>>().join(", "),
+        x => x.to_string(),
+    }
+}
+
+/// Only follows redirects that keep the host and the request method, so that the session
+/// headers are never sent to another host and requests are never silently turned into GETs.
+fn redirect_policy(no_follow_redirects: bool) -> Policy {
+    Policy::custom(move |attempt| {
+        let target = attempt.url().to_string();
+        if no_follow_redirects {
+            return attempt.error(format!("refusing redirect to {target}, pass the final url via --url"));
+        }
+
+        let origin = &attempt.previous()[0];
+        let same_origin = attempt.url().scheme() == origin.scheme()
+            && attempt.url().host_str() == origin.host_str()
+            && attempt.url().port_or_known_default() == origin.port_or_known_default();
+        let status = attempt.status();
+        let keeps_method = matches!(status, StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT);
+        if !same_origin {
+            attempt.error(format!(
+                "refusing redirect to another origin ({target}), pass the final url via --url"
+            ))
+        } else if !keeps_method {
+            attempt.error(format!(
+                "refusing redirect to {target} with status {status}, which may change the request method"
+            ))
+        } else if attempt.previous().len() > 10 {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Returns a hint for the likely cause of kanidm denying access to the given path when provisioning as `auth_user`.
+fn access_denied_suggestion(path: &str, auth_user: &str) -> String {
+    if path.ends_with("/_basic_secret") {
+        "Writing basic secrets requires a kanidm build with the patch from this repository, see https://github.com/oddlama/kanidm-provision#provisioning-oauth2-basic-secrets".to_string()
+    } else if path.contains(ENDPOINT_PERSON) || path.contains(ENDPOINT_SERVICE_ACCOUNT) {
+        format!("{auth_user} cannot modify high privilege accounts, e.g. members of idm_admins. Manage such accounts as admin or remove them from the state file")
+    } else if path.contains(ENDPOINT_OAUTH2) || path.contains(ENDPOINT_OAUTH2_IMAGE) {
+        format!("Managing oauth2 resource servers requires {auth_user} to be a member of idm_oauth2_admins, which is the default for idm_admin")
+    } else {
+        format!("Make sure --token-file or KANIDM_PROVISION_IDM_ADMIN_TOKEN contains the password of {auth_user} (set via --auth-user) and that {auth_user} still has the privileges required for the state file")
+    }
+}
+
+/// Builds the error for an unsuccessful response. The raw body is only attached in verbose mode.
+fn unsuccessful_response_error(status: StatusCode, path: &str, body: &str, client: &KanidmClient) -> Report {
+    let reason = match serde_json::from_str::<Value>(body) {
+        Ok(value) => describe_kanidm_error(&value),
+        Err(_) if body.trim().is_empty() => "<no body>".to_string(),
+        Err(_) => body.trim().to_string(),
+    };
+
+    let error = if status == StatusCode::FORBIDDEN || reason.to_lowercase().contains("accessdenied") {
+        eyre!("kanidm denied access to {path} ({status}): {reason}")
+            .suggestion(access_denied_suggestion(path, &client.auth_user))
+    } else {
+        eyre!("Server returned unsuccessful HTTP status ({status}): {reason}")
+    };
+    if client.verbose {
+        error.note(format!("body: {body}"))
+    } else {
+        error
+    }
 }
 
 impl ResponseExt for Response {
-    fn get_json_response(self) -> Result<Value> {
+    fn get_json_response(self, client: &KanidmClient) -> Result<Value> {
         let status = self.status();
-        let json: Result<Value> = self
-            .text()
-            .wrap_err("Response had no body")
-            .and_then(|x| serde_json::from_str(&x).wrap_err("Response wasn't json"));
-
+        let path = self.url().path().to_string();
+        let body = self.text().wrap_err("Response had no body")?;
         if !status.is_success() {
-            return Err(
-                eyre!("Server returned unsuccessful HTTP status ({status})").note(match json {
-                    Ok(ref value) => value.to_string(),
-                    Err(ref e) => e.to_string(),
-                }),
-            );
+            return Err(unsuccessful_response_error(status, &path, &body, client));
+        }
+
+        serde_json::from_str(&body).wrap_err("Response wasn't json")
+    }
+
+    /// Like `get_json_response`, but deserializes the body while it is being received
+    /// instead of buffering it completely first, which matters for large responses.
+    fn get_json_response_streaming<T: DeserializeOwned>(self, client: &KanidmClient) -> Result<T> {
+        if !self.status().is_success() {
+            return self
+                .get_json_response(client)
+                .and_then(|_| Err(eyre!("Unsuccessful response was not reported as an error")));
         }
 
-        json
+        // Reading from the response directly would cause many tiny reads through the blocking runtime
+        serde_json::from_reader(BufReader::new(self)).wrap_err("Response wasn't valid json")
     }
 
-    fn detailed_error_for_status(self) -> Result<Response> {
-        if let std::result::Result::Err(e) = self.error_for_status_ref() {
-            Err(e).wrap_err(format!("body: {}", self.text().unwrap_or("<no body>".to_owned())))
+    fn detailed_error_for_status(self, client: &KanidmClient) -> Result<Response> {
+        let status = self.status();
+        if status.is_client_error() || status.is_server_error() {
+            let path = self.url().path().to_string();
+            let body = self.text().unwrap_or_default();
+            Err(unsuccessful_response_error(status, &path, &body, client))
         } else {
             Ok(self)
         }
     }
 }
 
+/// The mechanism used to authenticate as the auth user.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AuthMechanism {
+    /// Authenticate using only a password.
+    #[default]
+    Password,
+    /// Authenticate using a TOTP code and a password.
+    /// The TOTP code is read from KANIDM_PROVISION_IDM_ADMIN_TOTP.
+    PasswordMfa,
+}
+
+impl AuthMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMechanism::Password => "password",
+            AuthMechanism::PasswordMfa => "passwordmfa",
+        }
+    }
+}
+
+/// Options that control how the client talks to kanidm.
+#[derive(Debug, Default, Clone)]
+pub struct ClientOptions {
+    /// DANGEROUS! Accept invalid TLS certificates, e.g. for testing instances.
+    pub accept_invalid_certs: bool,
+    /// Print the current and desired value of every attribute that is changed.
+    pub diff: bool,
+    /// Never send modifying requests, only record them.
+    pub dry_run: bool,
+    /// Wait up to this long for kanidm to become ready before authenticating.
+    pub wait_ready: Option<Duration>,
+    /// The upper bound for the exponential backoff between two readiness checks.
+    /// Defaults to [`DEFAULT_WAIT_READY_MAX_INTERVAL`].
+    pub wait_ready_max_interval: Option<Duration>,
+    /// Wait for a longer cooldown after this many consecutive connection failures while waiting
+    /// for kanidm to become ready. Defaults to [`DEFAULT_CIRCUIT_BREAKER_THRESHOLD`].
+    pub circuit_breaker_threshold: Option<u32>,
+    /// The cooldown used once the circuit breaker has opened.
+    /// Defaults to [`DEFAULT_CIRCUIT_BREAKER_COOLDOWN`].
+    pub circuit_breaker_cooldown: Option<Duration>,
+    /// Send this value as the Host header instead of the host given in the url.
+    pub host_header: Option<String>,
+    /// The mechanism used to authenticate as the auth user.
+    pub auth_mechanism: AuthMechanism,
+    /// The account used for provisioning. Defaults to [`DEFAULT_AUTH_USER`].
+    pub auth_user: Option<String>,
+    /// A file containing the password of the auth user. If not given, the password
+    /// is read from KANIDM_PROVISION_IDM_ADMIN_TOKEN.
+    pub token_file: Option<PathBuf>,
+    /// Refuse all redirects instead of following redirects to the same host.
+    pub no_follow_redirects: bool,
+    /// Wait at least this long between two consecutive requests.
+    pub min_request_interval: Option<Duration>,
+    /// Allow changing the uid or gid number of existing posix entities.
+    pub allow_id_changes: bool,
+    /// Attach the raw response body to errors returned by kanidm.
+    pub verbose: bool,
+    /// Ask for confirmation before removing more than this many members from a group at once.
+    pub confirm_large_changes: Option<usize>,
+    /// Assume yes for all confirmations, e.g. when running non-interactively.
+    pub yes: bool,
+    /// Ask for confirmation before deleting orphaned entities.
+    pub interactive: bool,
+    /// Use this server version for feature detection instead of querying it.
+    pub assume_version: Option<KanidmVersion>,
+    /// Connect to this unix domain socket instead of the host given in the url, which
+    /// then only provides the Host header. Only plain http urls are supported.
+    pub unix_socket: Option<PathBuf>,
+}
+
+/// The version of a kanidm server, used to skip attributes the server doesn't support yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KanidmVersion(pub u64, pub u64, pub u64);
+
+impl FromStr for KanidmVersion {
+    type Err = String;
+
+    /// Parses versions like `1.4.2`, ignoring any suffix like `-dev`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let version = s.split_once('-').map_or(s, |x| x.0);
+        let parts: Vec<u64> = version
+            .split('.')
+            .map(|x| x.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid kanidm version '{s}'"))?;
+        match parts[..] {
+            [major, minor, patch] => Ok(KanidmVersion(major, minor, patch)),
+            [major, minor] => Ok(KanidmVersion(major, minor, 0)),
+            _ => Err(format!("invalid kanidm version '{s}', expected e.g. 1.4.2")),
+        }
+    }
+}
+
+impl fmt::Display for KanidmVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// The header in which kanidm reports its version on every response.
+const HEADER_KANIDM_VERSION: &str = "x-kanidm-version";
+
+/// Oauth2 attributes that only exist since the given kanidm version. Attributes that are supported
+/// by all kanidm versions this tool ships patches for, like oauth2_allow_localhost_redirect, are not listed.
+const VERSIONED_OAUTH2_ATTRS: [(&str, KanidmVersion); 2] = [
+    ("oauth2_strict_redirect_uri", KanidmVersion(1, 5, 0)),
+    ("oauth2_device_flow_enable", KanidmVersion(1, 6, 0)),
+];
+
 pub struct KanidmClient {
     url: String,
     client: Client,
-    idm_admin_headers: HeaderMap,
+    auth_user: String,
+    auth_password: String,
+    auth_mechanism: AuthMechanism,
+    idm_admin_headers: RefCell<HeaderMap>,
+    diff: bool,
+    dry_run: bool,
+    allow_id_changes: bool,
+    verbose: bool,
+    confirm_large_changes: Option<usize>,
+    yes: bool,
+    interactive: bool,
+    changes: RefCell<Vec<String>>,
+    warnings: RefCell<Vec<String>>,
+    min_request_interval: Option<Duration>,
+    last_request: Cell<Option<Instant>>,
+    version: Option<KanidmVersion>,
+}
+
+/// Strip the `@domain` suffix that kanidm appends to names of referenced entities.
+pub fn strip_domain(name: &str) -> &str {
+    name.split_once('@').map(|x| x.0).unwrap_or(name)
+}
+
+/// Attributes that kanidm stores as unordered sets and may return in any order. All other
+/// attributes are ordered, so reordering them in the state causes an update, e.g. the first
+/// mail address is the primary one. Scope and claim map values are always sets.
+pub const UNORDERED_ATTRS: [&str; 2] = ["member", "oauth2_rs_origin"];
+
+/// Brings current and desired values into a comparable form. Members may be given with or
+/// without the `@domain` suffix, kanidm resolves both forms.
+fn normalize_attr_values(attr: &str, current_values: &mut Vec<String>, values: &mut [String]) {
+    if attr == "member" {
+        *current_values = current_values.iter().map(|x| strip_domain(x).to_string()).collect();
+        for value in values.iter_mut() {
+            *value = strip_domain(value).to_string();
+        }
+    }
+
+    if UNORDERED_ATTRS.contains(&attr) {
+        current_values.sort_unstable();
+        values.sort_unstable();
+    }
+}
+
+/// Whether the error was caused by the server rejecting an attribute it doesn't know.
+fn is_unknown_attr_error(error: &Report) -> bool {
+    error.chain().any(|x| {
+        let x = x.to_string().to_lowercase();
+        x.contains("invalidattribute") || x.contains("invalid_attribute")
+    })
 }
 
 pub fn get_value_array(attr: &str, existing_entities: &HashMap<String, Value>, name: &str) -> Result<Vec<String>> {
     let entity = existing_entities
         .get(name)
-        .ok_or_else(|| eyre!("Cannot update unknown oauth2 resource server {name}"))?;
+        .ok_or_else(|| eyre!("Cannot update unknown entity {name}"))?;
 
     let current_values = match entity.pointer(attr) {
         Some(Value::Array(x)) => x.iter().filter_map(|x| x.as_str().map(|x| x.to_string())).collect(),
@@ -71,58 +420,361 @@ pub fn get_value_array(attr: &str, existing_entities: &HashMap<String, Value>, n
     Ok(current_values)
 }
 
+/// Accepts connections on a random local TCP port and forwards each of them to a new connection
+/// to the given unix socket, since the http client can only connect over TCP. Returns the address
+/// of the local port. Other local users can reach the port while provisioning runs, but every
+/// request still has to be authenticated, exactly like on the regular listener of kanidm.
+#[cfg(unix)]
+fn forward_unix_socket(socket: &Path) -> Result<SocketAddr> {
+    use std::os::unix::net::UnixStream;
+
+    // Fail early if nothing listens on the socket, instead of with a vague error on the first request
+    UnixStream::connect(socket)
+        .wrap_err_with(|| format!("Cannot connect to unix socket {}", socket.display()))
+        .note("kanidm only serves its API over TCP, so the socket must be provided by e.g. a reverse proxy")?;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+    let socket = socket.to_path_buf();
+    std::thread::spawn(move || {
+        for tcp in listener.incoming().flatten() {
+            // Dropping the TCP connection makes the request fail with a connection error
+            let Ok(unix) = UnixStream::connect(&socket) else {
+                continue;
+            };
+            let (Ok(mut tcp_read), Ok(mut unix_write)) = (tcp.try_clone(), unix.try_clone()) else {
+                continue;
+            };
+
+            std::thread::spawn(move || {
+                let _ = std::io::copy(&mut tcp_read, &mut unix_write);
+                let _ = unix_write.shutdown(Shutdown::Write);
+            });
+            std::thread::spawn(move || {
+                let (mut unix_read, mut tcp_write) = (unix, tcp);
+                let _ = std::io::copy(&mut unix_read, &mut tcp_write);
+                let _ = tcp_write.shutdown(Shutdown::Write);
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+#[cfg(not(unix))]
+fn forward_unix_socket(_socket: &Path) -> Result<SocketAddr> {
+    bail!("--unix-socket is only supported on unix platforms")
+}
+
 impl KanidmClient {
-    pub fn new(url: &str, accept_invalid_certs: bool) -> Result<KanidmClient> {
+    pub fn new(url: &str, options: &ClientOptions) -> Result<KanidmClient> {
+        let mut url = url.to_string();
+        let mut host_header = options.host_header.clone();
+        if let Some(socket) = &options.unix_socket {
+            let parsed = Url::parse(&url).wrap_err_with(|| format!("Invalid url {url}"))?;
+            if parsed.scheme() != "http" {
+                bail!("--unix-socket requires an http:// url, TLS is not supported over the socket");
+            }
+            let Some(host) = parsed.host_str() else {
+                bail!("The url {url} has no host, which is required for the Host header");
+            };
+
+            // An explicitly given Host header still takes precedence
+            host_header.get_or_insert_with(|| match parsed.port() {
+                Some(port) => format!("{host}:{port}"),
+                None => host.to_string(),
+            });
+            let addr = forward_unix_socket(socket)?;
+            url = format!("http://{addr}{}", parsed.path().trim_end_matches('/'));
+        }
+
+        let mut default_headers = HeaderMap::new();
+        if let Some(host_header) = &host_header {
+            default_headers.insert(HOST, HeaderValue::from_str(host_header)?);
+        }
+
         let mut client = KanidmClient {
-            url: url.to_string(),
+            url,
             client: Client::builder()
-                .danger_accept_invalid_certs(accept_invalid_certs)
+                .danger_accept_invalid_certs(options.accept_invalid_certs)
+                .default_headers(default_headers)
+                .redirect(redirect_policy(options.no_follow_redirects))
                 .build()?,
-            idm_admin_headers: HeaderMap::new(),
+            auth_user: options
+                .auth_user
+                .clone()
+                .unwrap_or_else(|| DEFAULT_AUTH_USER.to_string()),
+            auth_password: match &options.token_file {
+                Some(token_file) => std::fs::read_to_string(token_file)
+                    .wrap_err_with(|| format!("failed to read {token_file:?}"))?
+                    .trim()
+                    .to_string(),
+                None => std::env::var("KANIDM_PROVISION_IDM_ADMIN_TOKEN")
+                    .context("KANIDM_PROVISION_IDM_ADMIN_TOKEN missing")?,
+            },
+            auth_mechanism: options.auth_mechanism,
+            idm_admin_headers: RefCell::new(HeaderMap::new()),
+            diff: options.diff,
+            dry_run: options.dry_run,
+            allow_id_changes: options.allow_id_changes,
+            verbose: options.verbose,
+            confirm_large_changes: options.confirm_large_changes,
+            yes: options.yes,
+            interactive: options.interactive,
+            changes: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
+            min_request_interval: options.min_request_interval,
+            last_request: Cell::new(None),
+            version: None,
         };
 
-        let (session_id, token) = client.auth(
-            "idm_admin",
-            &std::env::var("KANIDM_PROVISION_IDM_ADMIN_TOKEN").context("KANIDM_PROVISION_IDM_ADMIN_TOKEN missing")?,
-        )?;
-        client
-            .idm_admin_headers
-            .insert("X-KANIDM-AUTH-SESSION-ID", HeaderValue::from_str(&session_id)?);
-        client
-            .idm_admin_headers
-            .insert("Authorization", HeaderValue::from_str(&format!("Bearer {token}"))?);
+        if let Some(timeout) = options.wait_ready {
+            client.wait_ready(timeout, options)?;
+        }
 
+        client.version = match options.assume_version {
+            Some(version) => Some(version),
+            None => client.query_version()?,
+        };
+
+        client.authenticate()?;
         Ok(client)
     }
 
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns the version of the server, if it is known.
+    pub fn version(&self) -> Option<KanidmVersion> {
+        self.version
+    }
+
+    /// Reads the server version from the header kanidm adds to every response.
+    fn query_version(&self) -> Result<Option<KanidmVersion>> {
+        self.throttle();
+        let response = self
+            .client
+            .get(format!("{}{ENDPOINT_STATUS}", self.url))
+            .send()
+            .wrap_err("while querying the kanidm version")?;
+
+        Ok(response
+            .headers()
+            .get(HEADER_KANIDM_VERSION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok()))
+    }
+
+    /// Returns the minimum kanidm version required for the given oauth2 attribute,
+    /// if the server is known to be older than that.
+    fn missing_oauth2_attr_version(&self, attr: &str) -> Option<KanidmVersion> {
+        let version = self.version?;
+        VERSIONED_OAUTH2_ATTRS
+            .iter()
+            .find(|(x, required)| *x == attr && version < *required)
+            .map(|(_, required)| *required)
+    }
+
+    /// Returns all mutating requests that were made, or skipped in dry-run mode.
+    pub fn changes(&self) -> Vec<String> {
+        self.changes.borrow().clone()
+    }
+
+    /// Returns the number of changes that were applied so far.
+    pub fn change_count(&self) -> usize {
+        self.changes.borrow().len()
+    }
+
+    /// Records a warning, which is reported after provisioning has finished.
+    pub fn warn(&self, message: &str) {
+        self.warnings.borrow_mut().push(message.to_string());
+    }
+
+    /// Returns all warnings that were recorded so far.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    /// Sleeps until the minimum request interval has passed since the previous request.
+    fn throttle(&self) {
+        let Some(interval) = self.min_request_interval else {
+            return;
+        };
+
+        if let Some(last_request) = self.last_request.get() {
+            std::thread::sleep(interval.saturating_sub(last_request.elapsed()));
+        }
+        self.last_request.set(Some(Instant::now()));
+    }
+
+    /// Asks whether to continue with the given action, e.g. `remove 3 members from /v1/group/x`.
+    /// Without a terminal or with JSON logs the action is refused, unless --yes was given.
+    fn confirm(&self, action: &str) -> Result<()> {
+        if !std::io::stdin().is_terminal() {
+            bail!("Refusing to {action} without confirmation, pass --yes to allow it");
+        }
+        // The prompt is not a log line and would break consumers of the JSON output
+        if json_logs() {
+            bail!("Refusing to {action} without confirmation, --json-logs does not support prompts, pass --yes to allow it");
+        }
+
+        print!("Continue? [y/N] ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("Aborted, did not {action}");
+        }
+
+        Ok(())
+    }
+
+    /// Asks for confirmation if more members than the configured threshold would be removed at once.
+    fn confirm_member_removal(&self, endpoint: &str, name: &str, removed: &[&String]) -> Result<()> {
+        let Some(threshold) = self.confirm_large_changes else {
+            return Ok(());
+        };
+
+        if removed.len() <= threshold || self.dry_run || self.yes {
+            return Ok(());
+        }
+
+        log_event(
+            "Confirm",
+            &format!("removing {} members from {endpoint}/{name}", removed.len()),
+        );
+        for member in removed {
+            log_event("Remove", member);
+        }
+
+        self.confirm(&format!("remove {} members from {endpoint}/{name}", removed.len()))
+    }
+
+    /// Lists the given orphans and asks for confirmation before they are deleted, if running interactively.
+    pub fn confirm_orphan_removal(&self, orphans: &[(String, &'static str)]) -> Result<()> {
+        if !self.interactive || orphans.is_empty() || self.dry_run || self.yes {
+            return Ok(());
+        }
+
+        log_event("Confirm", &format!("removing {} orphaned entities", orphans.len()));
+        for (orphan, kind) in orphans {
+            log_event("Remove", &format!("{kind} {orphan}"));
+        }
+
+        self.confirm(&format!("remove {} orphaned entities", orphans.len()))
+    }
+
+    /// Polls the status endpoint until the server reports that it is ready.
+    /// The delay between checks grows exponentially and is randomized, so that many
+    /// instances started at the same time don't poll kanidm in lockstep.
+    fn wait_ready(&self, timeout: Duration, options: &ClientOptions) -> Result<()> {
+        log_event("Waiting", &format!("for {} to become ready", self.url));
+        let max_interval = options
+            .wait_ready_max_interval
+            .unwrap_or(DEFAULT_WAIT_READY_MAX_INTERVAL);
+        let threshold = options
+            .circuit_breaker_threshold
+            .unwrap_or(DEFAULT_CIRCUIT_BREAKER_THRESHOLD);
+        let cooldown = options
+            .circuit_breaker_cooldown
+            .unwrap_or(DEFAULT_CIRCUIT_BREAKER_COOLDOWN);
+
+        let start = Instant::now();
+        let mut interval = Duration::from_secs(1).min(max_interval);
+        let mut connection_failures = 0;
+        loop {
+            match self.client.get(format!("{}{ENDPOINT_STATUS}", self.url)).send() {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(_) => connection_failures = 0,
+                Err(_) => connection_failures += 1,
+            }
+
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                bail!("kanidm never became ready within {} seconds", timeout.as_secs());
+            }
+
+            let delay = if threshold > 0 && connection_failures >= threshold {
+                log_event(
+                    "Waiting",
+                    &format!("{connection_failures} connection failures, cooling down for {cooldown:?}"),
+                );
+                connection_failures = 0;
+                cooldown
+            } else {
+                // Sleep somewhere between half and the full interval
+                interval.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+            };
+
+            std::thread::sleep(delay.min(remaining));
+            interval = (interval * 2).min(max_interval);
+        }
+    }
+
+    /// Creates a new session for the auth user and uses it for all subsequent requests.
+    fn authenticate(&self) -> Result<()> {
+        let (session_id, token) = self.auth(&self.auth_user, &self.auth_password)?;
+        let mut headers = self.idm_admin_headers.borrow_mut();
+        headers.insert("X-KANIDM-AUTH-SESSION-ID", HeaderValue::from_str(&session_id)?);
+        headers.insert("Authorization", HeaderValue::from_str(&format!("Bearer {token}"))?);
+        Ok(())
+    }
+
     pub fn auth(&self, user: &str, password: &str) -> Result<(String, String)> {
         let init_response = self
             .client
             .post(format!("{}{ENDPOINT_AUTH}", self.url))
             .json(&json!({ "step": { "init": user } }))
             .send()?
-            .detailed_error_for_status()?;
+            .detailed_error_for_status(self)?;
 
         let session_id = init_response
             .headers()
             .get("X-KANIDM-AUTH-SESSION-ID")
-            .ok_or_eyre("No session id was returned by the server!")?;
+            .ok_or_eyre("No session id was returned by the server!")?
+            .clone();
+
+        let init_response = init_response.get_json_response(self)?;
+        let mechanism = self.auth_mechanism.as_str();
+        let offered_mechanisms: Vec<&str> = match init_response.pointer("/state/choose") {
+            Some(Value::Array(x)) => x.iter().filter_map(|x| x.as_str()).collect(),
+            _ => vec![],
+        };
+        if !offered_mechanisms.contains(&mechanism) {
+            bail!("Cannot authenticate as {user} using {mechanism}, the server only offered: {offered_mechanisms:?}");
+        }
 
         let _begin_response = self
             .client
             .post(format!("{}{ENDPOINT_AUTH}", self.url))
-            .header("X-KANIDM-AUTH-SESSION-ID", session_id)
-            .json(&json!({ "step": { "begin": "password" } }))
+            .header("X-KANIDM-AUTH-SESSION-ID", &session_id)
+            .json(&json!({ "step": { "begin": mechanism } }))
             .send()?
-            .get_json_response()?;
+            .get_json_response(self)?;
+
+        if self.auth_mechanism == AuthMechanism::PasswordMfa {
+            let totp: u32 = std::env::var("KANIDM_PROVISION_IDM_ADMIN_TOTP")
+                .context("KANIDM_PROVISION_IDM_ADMIN_TOTP missing")?
+                .trim()
+                .parse()
+                .context("KANIDM_PROVISION_IDM_ADMIN_TOTP is not a valid TOTP code")?;
+
+            self.client
+                .post(format!("{}{ENDPOINT_AUTH}", self.url))
+                .header("X-KANIDM-AUTH-SESSION-ID", &session_id)
+                .json(&json!({ "step": { "cred": { "totp": totp } } }))
+                .send()?
+                .get_json_response(self)?;
+        }
 
         let cred_response = self
             .client
             .post(format!("{}{ENDPOINT_AUTH}", self.url))
-            .header("X-KANIDM-AUTH-SESSION-ID", session_id)
+            .header("X-KANIDM-AUTH-SESSION-ID", &session_id)
             .json(&json!({ "step": { "cred": { "password": password } } }))
             .send()?
-            .get_json_response()?;
+            .get_json_response(self)?;
 
         let token = cred_response
             .pointer("/state/success")
@@ -133,31 +785,99 @@ impl KanidmClient {
         Ok((session_id.to_str()?.to_string(), token))
     }
 
+    /// Returns the domain name of the kanidm instance.
+    pub fn get_domain_name(&self) -> Result<String> {
+        let response = self
+            .client
+            .get(format!("{}{ENDPOINT_DOMAIN}", self.url))
+            .send_authenticated(self)?
+            .get_json_response(self)?;
+
+        // Depending on the version, kanidm returns either the domain entry or a list containing it
+        let entity = match &response {
+            Value::Array(xs) => xs.first(),
+            x => Some(x),
+        };
+
+        entity
+            .and_then(|x| x.pointer("/attrs/domain_name/0"))
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string())
+            .ok_or_else(|| eyre!("No domain name found in response: {response:?}"))
+    }
+
+    /// Returns all entities of the given endpoint by name. kanidm doesn't paginate these listings,
+    /// so the whole array is returned at once. It is parsed while being received and each entity
+    /// is moved into the map without copying, so memory usage stays proportional to the directory size.
     pub fn get_entities(&self, endpoint: &str) -> Result<HashMap<String, Value>> {
         assert!(endpoint.starts_with('/'));
 
-        let Value::Array(entities) = self
+        let entities: Vec<Value> = self
             .client
             .get(format!("{}{endpoint}", self.url))
-            .headers(self.idm_admin_headers.clone())
-            .send()?
-            .get_json_response()?
-        else {
-            bail!("Invalid json response: Toplevel is not an array");
-        };
+            .send_authenticated(self)?
+            .get_json_response_streaming(self)
+            .wrap_err("Invalid json response: Toplevel is not an array")?;
 
         Ok(entities
-            .iter()
+            .into_iter()
             .filter_map(|e| {
                 let name = e
                     .pointer("/attrs/name/0")
                     .and_then(|x| x.as_str())
                     .map(|x| x.to_string());
-                name.map(|x| (x, e.clone()))
+                name.map(|x| (x, e))
             })
             .collect())
     }
 
+    /// Fetches a single entity by name, returning `None` if it doesn't exist.
+    pub fn get_entity(&self, endpoint: &str, name: &str) -> Result<Option<Value>> {
+        assert!(endpoint.starts_with('/'));
+
+        let response = self
+            .client
+            .get(format!("{}{endpoint}/{name}", self.url))
+            .send_authenticated(self)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        match response.get_json_response(self)? {
+            Value::Null => Ok(None),
+            Value::Array(xs) => Ok(xs.into_iter().next()),
+            x => Ok(Some(x)),
+        }
+    }
+
+    /// Polls for an entity that was just created until it becomes visible, since some
+    /// backends don't return new entities immediately. Returns `None` in dry-run mode,
+    /// where nothing was actually created.
+    pub fn wait_for_created_entity(&self, endpoint: &str, name: &str) -> Result<Option<Value>> {
+        if self.dry_run {
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        let mut interval = Duration::from_millis(100);
+        loop {
+            if let Some(entity) = self.get_entity(endpoint, name)? {
+                return Ok(Some(entity));
+            }
+
+            let remaining = CREATED_ENTITY_VISIBILITY_TIMEOUT.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                bail!(
+                    "Created {endpoint}/{name}, but it did not become visible within {} seconds",
+                    CREATED_ENTITY_VISIBILITY_TIMEOUT.as_secs()
+                );
+            }
+
+            std::thread::sleep(interval.min(remaining));
+            interval = (interval * 2).min(Duration::from_secs(2));
+        }
+    }
+
     pub fn update_entity_attrs(
         &self,
         endpoint: &str,
@@ -168,54 +888,159 @@ impl KanidmClient {
         append: bool,
     ) -> Result<()> {
         let mut current_values = get_value_array(&format!("/attrs/{attr}"), existing_entities, name)?;
-
-        if attr == "member" {
-            current_values = current_values
-                .iter()
-                .map(|x| x.split_once('@').map(|x| x.0).unwrap_or(x).to_string())
-                .collect();
-            current_values.sort_unstable();
-            values.sort_unstable();
-        }
+        normalize_attr_values(attr, &mut current_values, &mut values);
 
         if current_values != values {
+            if attr == "member" && !append {
+                let removed: Vec<&String> = current_values.iter().filter(|x| !values.contains(x)).collect();
+                self.confirm_member_removal(endpoint, name, &removed)?;
+            }
+
             if values.is_empty() {
                 log_event("Deleting", &format!("{endpoint}/{name}/_attr/{attr}"));
                 self.client
                     .delete(format!("{}{endpoint}/{name}/_attr/{attr}", self.url))
-                    .headers(self.idm_admin_headers.clone())
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             } else if append {
                 log_event("Appending", &format!("{endpoint}/{name}/_attr/{attr}"));
                 self.client
                     .post(format!("{}{endpoint}/{name}/_attr/{attr}", self.url))
-                    .headers(self.idm_admin_headers.clone())
                     .json(&values)
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             } else {
                 log_event("Updating", &format!("{endpoint}/{name}/_attr/{attr}"));
                 self.client
                     .put(format!("{}{endpoint}/{name}/_attr/{attr}", self.url))
-                    .headers(self.idm_admin_headers.clone())
                     .json(&values)
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
+            }
+
+            if self.diff {
+                log_diff(&current_values, &values);
             }
         }
 
         Ok(())
     }
 
+    /// Like `update_entity_attrs`, but sends all changed attributes in a single request.
+    pub fn update_entity_attrs_batched(
+        &self,
+        endpoint: &str,
+        existing_entities: &HashMap<String, Value>,
+        name: &str,
+        attrs: Vec<(&str, Vec<String>)>,
+    ) -> Result<()> {
+        let mut changed_attrs = serde_json::Map::new();
+        let mut diffs = Vec::new();
+        for (attr, mut values) in attrs {
+            let mut current_values = get_value_array(&format!("/attrs/{attr}"), existing_entities, name)?;
+            normalize_attr_values(attr, &mut current_values, &mut values);
+
+            if current_values != values {
+                if attr == "member" {
+                    let removed: Vec<&String> = current_values.iter().filter(|x| !values.contains(x)).collect();
+                    self.confirm_member_removal(endpoint, name, &removed)?;
+                }
+
+                changed_attrs.insert(attr.to_string(), json!(values));
+                diffs.push((current_values, values));
+            }
+        }
+
+        if changed_attrs.is_empty() {
+            return Ok(());
+        }
+
+        let attr_names: Vec<&str> = changed_attrs.keys().map(|x| x.as_str()).collect();
+        log_event("Updating", &format!("{endpoint}/{name} {}", attr_names.join(", ")));
+        self.client
+            .patch(format!("{}{endpoint}/{name}", self.url))
+            .json(&json!({ "attrs": changed_attrs }))
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)?;
+
+        if self.diff {
+            for (current_values, values) in &diffs {
+                log_diff(current_values, values);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn update_unix_attrs(
+        &self,
+        endpoint: &str,
+        existing_entities: &HashMap<String, Value>,
+        name: &str,
+        attrs: &Value,
+    ) -> Result<()> {
+        let classes = get_value_array("/attrs/class", existing_entities, name)?;
+        let is_posix = classes.iter().any(|x| x == "posixgroup" || x == "posixaccount");
+
+        let Value::Object(attrs) = attrs else {
+            bail!("Invalid unix attrs for entity {name}: Not an object");
+        };
+
+        // Changing the id of an existing posix entity breaks the ownership of all of its files
+        if is_posix && !self.allow_id_changes {
+            for attr in ["gidnumber", "uidnumber"] {
+                let Some(desired) = attrs.get(attr).filter(|x| !x.is_null()) else {
+                    continue;
+                };
+
+                let current_values = get_value_array(&format!("/attrs/{attr}"), existing_entities, name)?;
+                if let Some(current) = current_values.first() {
+                    if *current != desired.to_string().trim_matches('"') {
+                        bail!("Refusing to change {attr} of {endpoint}/{name} from {current} to {desired}, pass --allow-id-changes if this is intended");
+                    }
+                }
+            }
+        }
+
+        let attrs_match = attrs.iter().all(|(attr, value)| {
+            let current_values =
+                get_value_array(&format!("/attrs/{attr}"), existing_entities, name).unwrap_or_default();
+            match value {
+                Value::Null => true,
+                Value::String(x) => current_values == [x.clone()],
+                x => current_values == [x.to_string()],
+            }
+        });
+
+        if !is_posix || !attrs_match {
+            log_event("Updating", &format!("{endpoint}/{name}/_unix"));
+            self.client
+                .post(format!("{}{endpoint}/{name}/_unix", self.url))
+                .json(attrs)
+                .send_authenticated(self)?
+                .detailed_error_for_status(self)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_entity_attr_values(&self, endpoint: &str, name: &str, attr: &str, values: &[String]) -> Result<()> {
+        log_event("Removing", &format!("{endpoint}/{name}/_attr/{attr} {values:?}"));
+        self.client
+            .delete(format!("{}{endpoint}/{name}/_attr/{attr}", self.url))
+            .json(values)
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)?;
+        Ok(())
+    }
+
     pub fn create_entity(&self, endpoint: &str, name: &str, payload: &Value) -> Result<()> {
         log_event("Creating", &format!("{endpoint}/{name}"));
         self.client
             .post(format!("{}{endpoint}", self.url))
-            .headers(self.idm_admin_headers.clone())
             .json(payload)
-            .send()?
-            .detailed_error_for_status()?;
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)?;
         Ok(())
     }
 
@@ -224,24 +1049,58 @@ impl KanidmClient {
         existing_entities: &HashMap<String, Value>,
         name: &str,
         attr: &str,
-        values: Vec<String>,
+        mut values: Vec<String>,
     ) -> Result<()> {
-        let current_values = get_value_array(&format!("/attrs/{attr}"), existing_entities, name)?;
+        if let Some(required) = self.missing_oauth2_attr_version(attr) {
+            // Disabled toggles match the behavior of servers that don't know the attribute
+            if values != ["false"] {
+                self.warn(&format!(
+                    "skipping {attr} for {name}, it requires kanidm >= {required} but the server runs {}",
+                    self.version.map_or_else(String::new, |x| x.to_string())
+                ));
+            }
+            return Ok(());
+        }
+
+        let mut current_values = get_value_array(&format!("/attrs/{attr}"), existing_entities, name)?;
+        normalize_attr_values(attr, &mut current_values, &mut values);
 
         if current_values != values {
             log_event("Updating", &format!("{ENDPOINT_OAUTH2}/{name} {attr}"));
-
             self.client
                 .patch(format!("{}{ENDPOINT_OAUTH2}/{name}", self.url))
-                .headers(self.idm_admin_headers.clone())
-                .json(&json!({ "attrs": { attr: values } }))
-                .send()?
-                .detailed_error_for_status()?;
+                .json(&json!({ "attrs": { attr: &values } }))
+                .send_authenticated(self)?
+                .detailed_error_for_status(self)?;
+
+            if self.diff {
+                log_diff(&current_values, &values);
+            }
         }
 
         Ok(())
     }
 
+    /// Like `update_oauth2_attrs`, but only warns if the server doesn't know the attribute,
+    /// which is the case for newer attributes on older kanidm versions.
+    pub fn update_oauth2_attrs_if_supported(
+        &self,
+        existing_entities: &HashMap<String, Value>,
+        name: &str,
+        attr: &str,
+        values: Vec<String>,
+    ) -> Result<()> {
+        match self.update_oauth2_attrs(existing_entities, name, attr, values) {
+            Err(e) if is_unknown_attr_error(&e) => {
+                self.warn(&format!(
+                    "skipping {attr} for {name}, the attribute is not supported by this kanidm version"
+                ));
+                Ok(())
+            }
+            x => x,
+        }
+    }
+
     pub fn update_oauth2_map(
         &self,
         endpoint_name: &str,
@@ -255,12 +1114,10 @@ impl KanidmClient {
 
         let mut current_values: Vec<_> = current_values
             .iter()
-            .find(|x| x.starts_with(&format!("{}@", group)))
-            .map(|x| {
-                x.split_once(": ")
-                    .map(|x| x.1)
-                    .unwrap_or(x)
-                    .trim_start_matches('{')
+            .filter_map(|x| x.split_once(": "))
+            .find(|(x, _)| strip_domain(x) == strip_domain(group))
+            .map(|(_, x)| {
+                x.trim_start_matches('{')
                     .trim_end_matches('}')
                     .split(", ")
                     .map(|e| e.trim_matches('"'))
@@ -276,17 +1133,15 @@ impl KanidmClient {
                 log_event("Deleting", &format!("{ENDPOINT_OAUTH2}/{name} {attr_name}/{group}"));
                 self.client
                     .delete(format!("{}{ENDPOINT_OAUTH2}/{name}/{endpoint_name}/{group}", self.url))
-                    .headers(self.idm_admin_headers.clone())
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             } else {
                 log_event("Updating", &format!("{ENDPOINT_OAUTH2}/{name} {attr_name}/{group}"));
                 self.client
                     .post(format!("{}{ENDPOINT_OAUTH2}/{name}/{endpoint_name}/{group}", self.url))
-                    .headers(self.idm_admin_headers.clone())
                     .json(&scopes)
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             }
         }
 
@@ -305,8 +1160,9 @@ impl KanidmClient {
 
         let mut current_values: Vec<_> = current_values
             .iter()
-            .find(|x| x.starts_with(&format!("{claim}:{group}@")))
-            .map(|x| x.split(':').nth(3).unwrap_or(x).trim_matches('"').split(',').collect())
+            .map(|x| x.split(':').collect::<Vec<_>>())
+            .find(|xs| xs.len() > 3 && xs[0] == claim && strip_domain(xs[1]) == strip_domain(group))
+            .map(|xs| xs[3].trim_matches('"').split(',').collect())
             .unwrap_or_else(Vec::new);
 
         current_values.sort_unstable();
@@ -324,9 +1180,8 @@ impl KanidmClient {
                         "{}{ENDPOINT_OAUTH2}/{name}/_claimmap/{claim}/{group}",
                         self.url
                     ))
-                    .headers(self.idm_admin_headers.clone())
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             } else {
                 log_event(
                     "Updating",
@@ -338,10 +1193,9 @@ impl KanidmClient {
                         "{}{ENDPOINT_OAUTH2}/{name}/_claimmap/{claim}/{group}",
                         self.url
                     ))
-                    .headers(self.idm_admin_headers.clone())
                     .json(&values)
-                    .send()?
-                    .detailed_error_for_status()?;
+                    .send_authenticated(self)?
+                    .detailed_error_for_status(self)?;
             }
         }
 
@@ -354,6 +1208,7 @@ impl KanidmClient {
         name: &str,
         claim: &str,
         join_type: &str,
+        has_values: bool,
     ) -> Result<()> {
         let current_values = get_value_array("/attrs/oauth2_rs_claim_map", existing_entities, name)?;
 
@@ -363,17 +1218,18 @@ impl KanidmClient {
             .and_then(|x| x.split(':').nth(2));
 
         let current = match delimiter {
-            Some(" ") => "ssv",
-            Some(",") => "csv",
-            Some(";") => "array",
-            _ => "array",
+            Some(" ") => Some("ssv"),
+            Some(",") => Some("csv"),
+            Some(";") => Some("array"),
+            // An unknown delimiter is always replaced by the declared join
+            Some(_) => None,
+            // A claim without values has no join, unless its values were only just created,
+            // in which case kanidm uses its default
+            None if !has_values => return Ok(()),
+            None => Some("array"),
         };
 
-        if !matches!(join_type, "ssv" | "csv" | "array") {
-            bail!("Invalid join_type ({join_type}) for oauth {name} claim {claim}");
-        }
-
-        if current != join_type {
+        if current != Some(join_type) {
             log_event(
                 "Updating",
                 &format!("{ENDPOINT_OAUTH2}/{name} oauth2_rs_claim_map_join/{claim}"),
@@ -381,42 +1237,178 @@ impl KanidmClient {
 
             self.client
                 .post(format!("{}{ENDPOINT_OAUTH2}/{name}/_claimmap/{claim}", self.url))
-                .headers(self.idm_admin_headers.clone())
                 .json(&join_type)
-                .send()?
-                .detailed_error_for_status()?;
+                .send_authenticated(self)?
+                .detailed_error_for_status(self)?;
         }
 
         Ok(())
     }
 
-    pub fn update_oauth2_basic_secret(&self, name: &str, secret_file: &str) -> Result<()> {
+    /// Reports a secret change without revealing either value. In diff mode this only
+    /// shows whether a secret was set before.
+    fn log_secret_update(&self, path: &str, had_secret: bool) {
+        log_event("Updating", &format!("{path} (secret redacted)"));
+        if self.diff {
+            let current = if had_secret { vec![REDACTED.to_string()] } else { vec![] };
+            log_diff(&current, &[REDACTED.to_string()]);
+        }
+    }
+
+    /// Compares the current basic secret with the desired one and only patches it on change.
+    /// The comparison also happens in dry-run mode, so that previews are accurate.
+    pub fn update_oauth2_basic_secret(&self, name: &str, desired_secret: &str) -> Result<()> {
         let current_secret = self
             .client
             .get(format!("{}{ENDPOINT_OAUTH2}/{name}/_basic_secret", self.url))
-            .headers(self.idm_admin_headers.clone())
-            .send()?
-            .get_json_response()?;
+            .send_authenticated(self)?
+            .get_json_response(self)?;
+
+        // The server returns null if no secret has been set yet
+        let current_secret = match current_secret {
+            Value::String(ref x) => Some(x.trim()),
+            Value::Null => None,
+            _ => bail!("Invalid basic secret response: Not a string"),
+        };
+
+        let desired_secret = desired_secret.trim();
+        if current_secret != Some(desired_secret) {
+            self.log_secret_update(
+                &format!("{ENDPOINT_OAUTH2}/{name}/_basic_secret"),
+                current_secret.is_some(),
+            );
+
+            let response = self
+                .client
+                .patch(format!("{}{ENDPOINT_OAUTH2}/{name}/_basic_secret", self.url))
+                .json(desired_secret)
+                .send_authenticated(self)
+                .wrap_err("Failed to update oauth2 basic secret! Did you compile kanidm with the necessary patch? Refer to https://github.com/oddlama/kanidm-provision for more information.")?;
+
+            // An unpatched kanidm either doesn't know the endpoint or denies writing the secret
+            let status = response.status();
+            if matches!(
+                status,
+                StatusCode::FORBIDDEN | StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+            ) {
+                let error = eyre!(
+                    "kanidm refused to set the basic secret of oauth2 '{name}' ({status}), it is probably missing the basic secret patch"
+                )
+                .suggestion(access_denied_suggestion("/_basic_secret", &self.auth_user));
+                if self.verbose {
+                    let body = response.text().unwrap_or_default();
+                    return Err(error.note(format!("body: {body}")));
+                }
+                return Err(error);
+            }
+
+            response.get_json_response(self)?;
+        }
 
-        let current_secret = current_secret
-            .as_str()
-            .ok_or_eyre("Invalid basic secret response: Not a string")?;
+        Ok(())
+    }
+
+    pub fn update_person_radius_secret(&self, name: &str, secret_file: &str) -> Result<()> {
+        let current_secret = self
+            .client
+            .get(format!("{}{ENDPOINT_PERSON}/{name}/_radius", self.url))
+            .send_authenticated(self)?
+            .get_json_response(self)?;
 
         let desired_secret =
             std::fs::read_to_string(secret_file).wrap_err_with(|| format!("failed to read {:?}", secret_file))?;
         let desired_secret = desired_secret.trim();
 
-        if current_secret != desired_secret {
-            log_event("Updating", &format!("{ENDPOINT_OAUTH2}/{name}/_basic_secret"));
+        if current_secret.as_str().map(str::trim) != Some(desired_secret) {
+            self.log_secret_update(&format!("{ENDPOINT_PERSON}/{name}/_radius"), current_secret.is_string());
 
-            self
-                .client
-                .patch(format!("{}{ENDPOINT_OAUTH2}/{name}/_basic_secret", self.url))
-                .headers(self.idm_admin_headers.clone())
+            self.client
+                .patch(format!("{}{ENDPOINT_PERSON}/{name}/_radius", self.url))
                 .json(desired_secret)
-                .send()
-                .wrap_err("Failed to update oauth2 basic secret! Did you compile kanidm with the necessary patch? Refer to https://github.com/oddlama/kanidm-provision for more information.")?
-                .get_json_response()?;
+                .send_authenticated(self)
+                .wrap_err(
+                    "Failed to update person radius secret! Does your kanidm server support setting radius secrets?",
+                )?
+                .detailed_error_for_status(self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the unix password of a posix account, which is separate from its kanidm credentials.
+    pub fn set_person_unix_password(&self, name: &str, password: &str) -> Result<()> {
+        log_event(
+            "Updating",
+            &format!("{ENDPOINT_PERSON}/{name}/_unix/_credential (secret redacted)"),
+        );
+        self.client
+            .put(format!("{}{ENDPOINT_PERSON}/{name}/_unix/_credential", self.url))
+            .json(&json!({ "value": password }))
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)?;
+        Ok(())
+    }
+
+    /// Issues a new sync token for the given sync account, which invalidates any previous token.
+    /// Returns None in dry-run mode.
+    pub fn generate_sync_token(&self, name: &str) -> Result<Option<String>> {
+        log_event("Updating", &format!("{ENDPOINT_SYNC_ACCOUNT}/{name}/_sync_token"));
+        let token = self
+            .client
+            .post(format!("{}{ENDPOINT_SYNC_ACCOUNT}/{name}/_sync_token", self.url))
+            .json("kanidm-provision")
+            .send_authenticated(self)?
+            .get_json_response(self)?;
+
+        Ok(token.as_str().map(|x| x.to_string()))
+    }
+
+    /// Sets the primary password of a person using a credential update session.
+    pub fn set_person_password(&self, name: &str, password: &str) -> Result<()> {
+        log_event("Updating", &format!("{ENDPOINT_PERSON}/{name}/_credential"));
+        let session = self
+            .client
+            .get(format!("{}{ENDPOINT_PERSON}/{name}/_credential/_update", self.url))
+            .send_authenticated(self)?
+            .get_json_response(self)?;
+        let session_token = session
+            .get(0)
+            .ok_or_eyre("Invalid credential update response: No session token")?;
+
+        self.client
+            .post(format!("{}{ENDPOINT_CREDENTIAL}/_update", self.url))
+            .json(&json!([{ "password": password }, session_token]))
+            .send_authenticated(self)?
+            .get_json_response(self)?;
+
+        self.client
+            .post(format!("{}{ENDPOINT_CREDENTIAL}/_commit", self.url))
+            .json(session_token)
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)?;
+
+        Ok(())
+    }
+
+    pub fn update_oauth2_image(&self, name: &str, image: Vec<u8>, mime_type: &str) -> Result<()> {
+        let current_image = self
+            .client
+            .get(format!("{}{ENDPOINT_OAUTH2_IMAGE}/{name}", self.url))
+            .send_authenticated(self)?;
+
+        let is_current = current_image.status().is_success() && current_image.bytes()?.as_ref() == image.as_slice();
+        if !is_current {
+            log_event("Updating", &format!("{ENDPOINT_OAUTH2}/{name}/_image"));
+
+            let extension = mime_type.trim_start_matches("image/").trim_end_matches("+xml");
+            let part = Part::bytes(image)
+                .file_name(format!("{name}.{extension}"))
+                .mime_str(mime_type)?;
+            self.client
+                .post(format!("{}{ENDPOINT_OAUTH2}/{name}/_image", self.url))
+                .multipart(Form::new().part("image", part))
+                .send_authenticated(self)?
+                .detailed_error_for_status(self)?;
         }
 
         Ok(())
@@ -426,9 +1418,8 @@ impl KanidmClient {
         log_event("Deleting", &format!("{endpoint}/{entity}"));
         self.client
             .delete(format!("{}{endpoint}/{entity}", self.url))
-            .headers(self.idm_admin_headers.clone())
-            .send()?
-            .detailed_error_for_status()
+            .send_authenticated(self)?
+            .detailed_error_for_status(self)
             .note("Is the name already in use by another entity?")?;
         Ok(())
     }
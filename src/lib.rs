@@ -0,0 +1,1918 @@
+#![doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md"))]
+
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use client::{
+    KanidmClient, ENDPOINT_GROUP, ENDPOINT_OAUTH2, ENDPOINT_PERSON, ENDPOINT_SERVICE_ACCOUNT, ENDPOINT_SYNC_ACCOUNT,
+};
+use color_eyre::{
+    eyre::{bail, eyre, Result, WrapErr},
+    owo_colors::OwoColorize,
+    Section,
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use state::{parse_duration, Hooks, RemoveState, State, BUILTIN_ACCOUNTS};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use url::Url;
+
+use crate::client::{get_value_array, strip_domain};
+
+pub mod client;
+pub mod state;
+
+pub const PROVISION_TRACKING_GROUP: &str = "ext_idm_provisioned_entities";
+
+/// Marks values in an exported state that could not be read from kanidm and must be filled in by hand.
+pub const EXPORT_PLACEHOLDER: &str = "PLACEHOLDER";
+
+/// Whether log output is emitted as tracing events instead of being printed,
+/// which requires the `tracing` feature and an installed subscriber.
+#[cfg(feature = "tracing")]
+fn use_tracing() -> bool {
+    tracing::dispatcher::has_been_set()
+}
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Makes all log functions print one JSON object per line instead of colored text.
+pub fn set_json_logs(enabled: bool) {
+    JSON_LOGS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether log output is printed as JSON, see [`set_json_logs`].
+pub(crate) fn json_logs() -> bool {
+    JSON_LOGS.load(Ordering::Relaxed)
+}
+
+/// Prints a single JSON log line. Messages that start with an entity path like
+/// `/v1/group/name attr` additionally get the endpoint and entity as separate fields.
+fn log_json(level: &str, event: &str, message: &str, extra: Value) {
+    let mut line = json!({ "level": level, "event": event, "message": message });
+    let path = message.split_whitespace().next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').collect();
+    if let ["", "v1", kind, entity, ..] = segments[..] {
+        line["endpoint"] = json!(format!("/v1/{kind}"));
+        line["entity"] = json!(entity);
+    }
+    if let (Value::Object(line), Value::Object(extra)) = (&mut line, extra) {
+        line.extend(extra);
+    }
+    println!("{line}");
+}
+
+pub fn log_status(message: &str) {
+    #[cfg(feature = "tracing")]
+    if use_tracing() {
+        tracing::info!(kind = "status", "{message}");
+        return;
+    }
+
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        return log_json("info", "status", message, Value::Null);
+    }
+
+    println!("{}", message.blue().bold());
+}
+
+pub fn log_event(event: &str, message: &str) {
+    #[cfg(feature = "tracing")]
+    if use_tracing() {
+        tracing::info!(kind = "event", event, "{message}");
+        return;
+    }
+
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        return log_json("info", &event.to_lowercase(), message, Value::Null);
+    }
+
+    println!("{:>12} {}", event.green().bold(), message);
+}
+
+pub fn log_progress(kind: &str, current: usize, total: usize) {
+    #[cfg(feature = "tracing")]
+    if use_tracing() {
+        tracing::debug!(kind = "progress", entities = kind, current, total);
+        return;
+    }
+
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        let extra = json!({ "current": current, "total": total });
+        return log_json("debug", "progress", kind, extra);
+    }
+
+    println!("{:>12} {kind} {current}/{total}", "Progress".cyan().bold());
+}
+
+pub fn log_warn(message: &str) {
+    #[cfg(feature = "tracing")]
+    if use_tracing() {
+        tracing::warn!("{message}");
+        return;
+    }
+
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        return log_json("warn", "warning", message, Value::Null);
+    }
+
+    println!("{}", format!("WARN: {message}").yellow().bold());
+}
+
+pub fn log_diff(current: &[String], desired: &[String]) {
+    #[cfg(feature = "tracing")]
+    if use_tracing() {
+        tracing::info!(kind = "diff", ?current, ?desired);
+        return;
+    }
+
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        let extra = json!({ "current": current, "desired": desired });
+        return log_json("info", "diff", "", extra);
+    }
+
+    println!("{:>12} {}", "-".red().bold(), format!("{current:?}").red());
+    println!("{:>12} {}", "+".green().bold(), format!("{desired:?}").green());
+}
+
+/// Options that control how the state is provisioned.
+#[derive(Debug, Default, Clone)]
+pub struct ProvisionOptions {
+    /// Do not automatically remove orphaned entities that were previously provisioned
+    /// but have since been removed from the state.
+    pub no_auto_remove: bool,
+    /// A group to which all persons with a radius secret will be added.
+    pub radius_group: Option<String>,
+    /// Entities that are removed regardless of whether they were provisioned by this tool.
+    pub remove_state: Option<RemoveState>,
+    /// Replaces {domain} in oauth2 origins. If not given, the domain is queried from kanidm.
+    pub domain: Option<String>,
+    /// Abort at the next entity boundary once this point in time has passed.
+    pub deadline: Option<Instant>,
+    /// Abort at the next entity boundary once this flag is set, e.g. by a signal handler.
+    pub interrupted: Option<Arc<AtomicBool>>,
+    /// Delete and recreate these oauth2 resource servers, even if their type didn't change.
+    pub force_recreate: Vec<String>,
+    /// Secrets that can be referenced by key from the state, e.g. via basicSecretKey.
+    pub secrets: HashMap<String, String>,
+    /// Whether absent optional attributes are removed or left untouched.
+    pub merge_mode: MergeMode,
+    /// Print the number of processed entities while syncing.
+    pub progress: bool,
+    /// Run the hooks declared in the state. Provisioning fails if the state declares hooks otherwise.
+    pub allow_hooks: bool,
+    /// Disable orphaned entities first and only delete them after a grace period.
+    pub soft_delete: Option<SoftDelete>,
+}
+
+/// Configures the two-phase removal of orphaned entities.
+#[derive(Debug, Clone)]
+pub struct SoftDelete {
+    /// A JSON file that records when each orphan was disabled, as unix timestamps by name.
+    pub state_file: PathBuf,
+    /// How long an orphan stays disabled before it is deleted.
+    pub grace_period: Duration,
+}
+
+/// Determines what happens to optional attributes that are absent from the state.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MergeMode {
+    /// Absent attributes are removed from kanidm.
+    #[default]
+    Replace,
+    /// Absent attributes are left untouched in kanidm.
+    Merge,
+}
+
+impl MergeMode {
+    /// Whether an optional attribute with the given value should be synced.
+    pub fn syncs<T>(&self, value: &Option<T>) -> bool {
+        value.is_some() || *self == MergeMode::Replace
+    }
+}
+
+/// A summary of what was changed by [`provision`].
+#[derive(Debug, Default, Clone)]
+pub struct ProvisionReport {
+    /// All modifying requests as "METHOD path". In dry-run mode, these were not sent to kanidm.
+    pub changes: Vec<String>,
+    /// The names and types of all orphaned entities that were removed.
+    pub removed_orphans: Vec<(String, &'static str)>,
+    /// The names of all entities that were removed because they were listed in the remove state.
+    pub removed_entities: Vec<String>,
+    /// Warnings about ignored or questionable settings in the state file.
+    pub warnings: Vec<String>,
+}
+
+/// Aborts provisioning between two entities once the deadline has passed or an interrupt
+/// was requested, so that no entity is left half-way updated.
+pub struct Deadline {
+    at: Option<Instant>,
+    interrupted: Option<Arc<AtomicBool>>,
+    total: usize,
+    processed: Cell<usize>,
+}
+
+impl Deadline {
+    pub fn new(at: Option<Instant>, interrupted: Option<Arc<AtomicBool>>, state: &State) -> Deadline {
+        Deadline {
+            at,
+            interrupted,
+            total: state.groups.len() + state.persons.len() + state.systems.oauth2.len() + state.sync_accounts.len(),
+            processed: Cell::new(0),
+        }
+    }
+
+    /// Fails if the deadline has passed, otherwise counts the next entity as processed.
+    pub fn next_entity(&self, kanidm_client: &KanidmClient) -> Result<()> {
+        self.check(kanidm_client)?;
+        self.processed.set(self.processed.get() + 1);
+        Ok(())
+    }
+
+    /// Fails if the deadline has passed or an interrupt was requested.
+    pub fn check(&self, kanidm_client: &KanidmClient) -> Result<()> {
+        let reason = if self.interrupted.as_ref().is_some_and(|x| x.load(Ordering::SeqCst)) {
+            "Interrupted"
+        } else if self.at.is_some_and(|x| Instant::now() >= x) {
+            "Deadline exceeded"
+        } else {
+            return Ok(());
+        };
+
+        Err(eyre!(
+            "{reason} after processing {} of {} entities",
+            self.processed.get(),
+            self.total
+        )
+        .note(format!(
+            "{} change(s) were applied before aborting, all remaining entities were left untouched",
+            kanidm_client.change_count()
+        )))
+    }
+}
+
+/// Return a map of all tracked entities and ensure that their names are unique.
+pub fn all_tracked_entities(state: &State) -> Result<Vec<String>> {
+    let mut entity_names: HashMap<_, Vec<&str>> = HashMap::new();
+    for i in state.groups.keys() {
+        entity_names.entry(i.to_owned()).or_default().push("group");
+    }
+    for i in state.persons.keys() {
+        entity_names.entry(i.to_owned()).or_default().push("person");
+    }
+    for i in state.systems.oauth2.keys() {
+        entity_names.entry(i.to_owned()).or_default().push("oauth2");
+    }
+    for i in state.sync_accounts.keys() {
+        entity_names.entry(i.to_owned()).or_default().push("sync_account");
+    }
+
+    // kanidm requires names to be unique across all kinds of entities, so this can't be relaxed
+    let mut duplicates: Vec<(&String, &Vec<&str>)> = entity_names.iter().filter(|(_, x)| x.len() > 1).collect();
+    if !duplicates.is_empty() {
+        duplicates.sort();
+        let mut error = eyre!(
+            "{} name(s) are used by multiple entities, but kanidm requires names to be unique across groups, persons, oauth2 resource servers and sync accounts",
+            duplicates.len()
+        );
+        for kind in ["group", "person", "oauth2", "sync_account"] {
+            let names: Vec<&str> = duplicates
+                .iter()
+                .filter(|(_, kinds)| kinds.contains(&kind))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            if !names.is_empty() {
+                error = error.note(format!("{kind}: {}", names.join(", ")));
+            }
+        }
+
+        let (name, kinds) = duplicates[0];
+        return Err(error.suggestion(format!(
+            "Rename all but one of them, e.g. '{name}' of the {} to '{name}-{}'",
+            kinds[1], kinds[1]
+        )));
+    }
+
+    if let Some(kinds) = entity_names.get(PROVISION_TRACKING_GROUP) {
+        bail!(
+            "The {} '{PROVISION_TRACKING_GROUP}' uses the name of the group that tracks provisioned entities",
+            kinds.join("/")
+        );
+    }
+
+    Ok(entity_names.keys().cloned().collect())
+}
+
+/// Runs all validations that don't require contacting kanidm. Everything else is already
+/// validated while parsing the state.
+pub fn validate(state: &State) -> Result<()> {
+    all_tracked_entities(state)?;
+    Ok(())
+}
+
+macro_rules! update_attrs {
+    ($kanidm_client:expr, $endpoint:expr, $existing:expr, $name:expr, [ $( $key:literal : $value:expr ),*, ]) => {
+        $kanidm_client
+            .update_entity_attrs_batched($endpoint, $existing, $name, vec![$(($key, $value)),*])
+            .wrap_err_with(|| format!("while updating {} of {}/{}", [$($key),*].join(", "), $endpoint, $name))?;
+    };
+}
+
+macro_rules! update_oauth2 {
+    ($kanidm_client:expr, $existing:expr, $name:expr, [ $( $key:literal : $value:expr ),*, ]) => {
+        $(
+            if let Some(value) = $value {
+                $kanidm_client.update_oauth2_attrs($existing, $name, $key, vec![value])
+            } else {
+                $kanidm_client.update_oauth2_attrs($existing, $name, $key, vec![])
+            }
+            .wrap_err_with(|| format!("while updating {} of oauth2 '{}'", $key, $name))?;
+        )*
+    };
+}
+
+/// Returns whether the existing entity with the given name has the given class.
+fn has_class(existing_entities: &HashMap<String, Value>, name: &str, class: &str) -> bool {
+    existing_entities
+        .get(name)
+        .and_then(|x| x.pointer("/attrs/class"))
+        .and_then(|x| x.as_array())
+        .is_some_and(|x| x.iter().any(|x| x.as_str() == Some(class)))
+}
+
+/// Runs the hook that matches what happened to an entity during its sync step, if any.
+/// The entity counts as updated if any change was applied since `changes_before`.
+fn run_hooks(
+    kanidm_client: &KanidmClient,
+    hooks: Option<&Hooks>,
+    kind: &str,
+    name: &str,
+    created: bool,
+    changes_before: usize,
+) -> Result<()> {
+    let Some(hooks) = hooks else {
+        return Ok(());
+    };
+
+    let (hook, command) = if created {
+        ("postCreate", &hooks.post_create)
+    } else if kanidm_client.change_count() > changes_before {
+        ("postUpdate", &hooks.post_update)
+    } else {
+        return Ok(());
+    };
+    let Some(command) = command else {
+        return Ok(());
+    };
+
+    if kanidm_client.is_dry_run() {
+        log_event("Skipped", &format!("{hook} hook of {kind} {name}"));
+        return Ok(());
+    }
+
+    log_event("Running", &format!("{hook} hook of {kind} {name}"));
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("KANIDM_PROVISION_ENTITY_KIND", kind)
+        .env("KANIDM_PROVISION_ENTITY_NAME", name)
+        .status()
+        .wrap_err_with(|| format!("failed to run {hook} hook of {kind} '{name}'"))?;
+
+    if !status.success() {
+        let message = format!("{hook} hook of {kind} '{name}' failed with {status}");
+        if hooks.abort_on_failure {
+            bail!(message);
+        }
+        kanidm_client.warn(&message);
+    }
+
+    Ok(())
+}
+
+pub fn sync_groups(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    existing_groups: &mut HashMap<String, Value>,
+    preexisting_entity_names: &HashSet<String>,
+    options: &ProvisionOptions,
+    deadline: &Deadline,
+) -> Result<()> {
+    log_status("Syncing groups");
+    for (i, (name, group)) in state.groups.iter().enumerate() {
+        deadline.next_entity(kanidm_client)?;
+        if options.progress {
+            log_progress("groups", i + 1, state.groups.len());
+        }
+        if group.present {
+            let changes_before = kanidm_client.change_count();
+            let created = !existing_groups.contains_key(name);
+            if created {
+                if preexisting_entity_names.contains(name) {
+                    bail!("Cannot create group '{name}' because the name is already in use by another entity!");
+                }
+
+                kanidm_client
+                    .create_entity(ENDPOINT_GROUP, name, &json!({ "attrs": { "name": [ name ] } }))
+                    .wrap_err_with(|| format!("while creating group '{name}'"))?;
+                if let Some(entity) = kanidm_client.wait_for_created_entity(ENDPOINT_GROUP, name)? {
+                    existing_groups.insert(name.clone(), entity);
+                }
+            }
+
+            if kanidm_client.is_dry_run() && !existing_groups.contains_key(name) {
+                // The group would have been created, so there is nothing to compare against
+                continue;
+            }
+
+            let mut attrs = Vec::new();
+            if let Some(account_policy) = &group.account_policy {
+                if !has_class(existing_groups, name, "account_policy") {
+                    kanidm_client
+                        .update_entity_attrs(
+                            ENDPOINT_GROUP,
+                            existing_groups,
+                            name,
+                            "class",
+                            vec!["account_policy".to_string()],
+                            true,
+                        )
+                        .wrap_err_with(|| format!("while enabling the account policy of group '{name}'"))?;
+                }
+
+                attrs.extend(account_policy.attrs()?);
+            }
+
+            if let Some(member_filter) = &group.member_filter {
+                if !has_class(existing_groups, name, "dyngroup") {
+                    kanidm_client
+                        .update_entity_attrs(
+                            ENDPOINT_GROUP,
+                            existing_groups,
+                            name,
+                            "class",
+                            vec!["dyngroup".to_string()],
+                            true,
+                        )
+                        .wrap_err_with(|| format!("while making group '{name}' a dynamic group"))?;
+                }
+
+                attrs.push(("dyngroup_filter", vec![member_filter.clone()]));
+            }
+
+            // An existing description is only removed if explicitly requested
+            if group.description.is_some() || group.clear_description {
+                attrs.push((
+                    "description",
+                    group.description.clone().map_or_else(Vec::new, |x| vec![x]),
+                ));
+            }
+            attrs.extend(
+                group
+                    .extra_attrs
+                    .iter()
+                    .map(|(attr, values)| (attr.as_str(), values.clone())),
+            );
+
+            kanidm_client
+                .update_entity_attrs_batched(ENDPOINT_GROUP, existing_groups, name, attrs)
+                .wrap_err_with(|| format!("while syncing attributes of group '{name}'"))?;
+
+            if group.unix.is_some() || group.sudo {
+                // Granting sudo happens by referencing the posix group in the host's sudoers,
+                // so the group must be known to the kanidm unix integration.
+                let gid_number = group.unix.as_ref().and_then(|x| x.gid_number);
+                kanidm_client
+                    .update_unix_attrs(
+                        ENDPOINT_GROUP,
+                        existing_groups,
+                        name,
+                        &json!({ "gidnumber": gid_number }),
+                    )
+                    .wrap_err_with(|| format!("while syncing unix attributes of group '{name}'"))?;
+            }
+
+            run_hooks(
+                kanidm_client,
+                group.hooks.as_ref(),
+                "group",
+                name,
+                created,
+                changes_before,
+            )?;
+        } else if existing_groups.contains_key(name) {
+            kanidm_client
+                .delete_entity(ENDPOINT_GROUP, name)
+                .wrap_err_with(|| format!("while deleting group '{name}'"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the password from the given file, or generates a new one and writes it
+/// to the file (only readable by the owner) if the file doesn't exist yet.
+fn read_or_generate_password(password_file: &str) -> Result<String> {
+    if Path::new(password_file).exists() {
+        let password =
+            std::fs::read_to_string(password_file).wrap_err_with(|| format!("failed to read {:?}", password_file))?;
+        return Ok(password.trim().to_string());
+    }
+
+    let password: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(password_file)
+        .wrap_err_with(|| format!("failed to create {:?}", password_file))?;
+    file.write_all(password.as_bytes())?;
+
+    Ok(password)
+}
+
+pub fn sync_persons(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    existing_persons: &mut HashMap<String, Value>,
+    preexisting_entity_names: &HashSet<String>,
+    options: &ProvisionOptions,
+    deadline: &Deadline,
+) -> Result<()> {
+    log_status("Syncing persons");
+    // The built-in accounts are service accounts in kanidm, so they are never listed as persons
+    let has_builtin_accounts = state.persons.keys().any(|x| BUILTIN_ACCOUNTS.contains(&x.as_str()));
+    let existing_service_accounts = if has_builtin_accounts {
+        kanidm_client.get_entities(ENDPOINT_SERVICE_ACCOUNT)?
+    } else {
+        HashMap::new()
+    };
+
+    for (i, (name, person)) in state.persons.iter().enumerate() {
+        deadline.next_entity(kanidm_client)?;
+        if options.progress {
+            log_progress("persons", i + 1, state.persons.len());
+        }
+        if BUILTIN_ACCOUNTS.contains(&name.as_str()) {
+            // Built-in accounts always exist and only support a limited set of attributes
+            if !existing_service_accounts.contains_key(name) {
+                bail!("Built-in account '{name}' was not found as a service account");
+            }
+
+            let mut attrs = vec![("displayname", vec![person.display_name.clone()])];
+            if options.merge_mode.syncs(&person.mail_addresses) || person.clear_mail_addresses {
+                attrs.push(("mail", person.mail_addresses.clone().unwrap_or_else(Vec::new)));
+            }
+            kanidm_client
+                .update_entity_attrs_batched(ENDPOINT_SERVICE_ACCOUNT, &existing_service_accounts, name, attrs)
+                .wrap_err_with(|| format!("while syncing attributes of built-in account '{name}'"))?;
+            continue;
+        }
+
+        if person.present {
+            let changes_before = kanidm_client.change_count();
+            let mut created = false;
+            if !existing_persons.contains_key(name) {
+                if preexisting_entity_names.contains(name) {
+                    bail!("Cannot create person '{name}' because the name is already in use by another entity!");
+                }
+
+                kanidm_client
+                    .create_entity(
+                        ENDPOINT_PERSON,
+                        name,
+                        &json!({ "attrs": {
+                            "name": [ name ],
+                            "displayname": [ person.display_name ]
+                        }}),
+                    )
+                    .wrap_err_with(|| format!("while creating person '{name}'"))?;
+                if let Some(entity) = kanidm_client.wait_for_created_entity(ENDPOINT_PERSON, name)? {
+                    existing_persons.insert(name.clone(), entity);
+                }
+                created = true;
+            }
+
+            if kanidm_client.is_dry_run() && !existing_persons.contains_key(name) {
+                // The person would have been created, so there is nothing to compare against
+                continue;
+            }
+
+            if let Some(password_file) = person.generate_password_to.as_ref().filter(|_| created) {
+                let password = read_or_generate_password(password_file)
+                    .wrap_err_with(|| format!("while generating password for person '{name}'"))?;
+                kanidm_client
+                    .set_person_password(name, &password)
+                    .wrap_err_with(|| format!("while setting password of person '{name}'"))?;
+            }
+
+            let mut attrs = vec![("displayname", vec![person.display_name.clone()])];
+            if options.merge_mode.syncs(&person.legal_name) || person.clear_legal_name {
+                attrs.push((
+                    "legalname",
+                    person.legal_name.clone().map_or_else(Vec::new, |x| vec![x]),
+                ));
+            }
+            if options.merge_mode.syncs(&person.mail_addresses) || person.clear_mail_addresses {
+                attrs.push(("mail", person.mail_addresses.clone().unwrap_or_else(Vec::new)));
+            }
+            attrs.extend(
+                person
+                    .extra_attrs
+                    .iter()
+                    .map(|(attr, values)| (attr.as_str(), values.clone())),
+            );
+
+            kanidm_client
+                .update_entity_attrs_batched(ENDPOINT_PERSON, existing_persons, name, attrs)
+                .wrap_err_with(|| format!("while syncing attributes of person '{name}'"))?;
+
+            // The expiry is derived only once, so that later runs don't keep pushing it forward
+            if let Some(expire_in) = &person.account_expire_in {
+                let current_expiry = get_value_array("/attrs/account_expire", existing_persons, name)?;
+                if current_expiry.is_empty() {
+                    kanidm_client
+                        .update_entity_attrs(
+                            ENDPOINT_PERSON,
+                            existing_persons,
+                            name,
+                            "account_expire",
+                            vec![account_expiry_in(expire_in)?],
+                            false,
+                        )
+                        .wrap_err_with(|| format!("while setting account expiry of person '{name}'"))?;
+                }
+            }
+
+            if let Some(secret_file) = &person.radius_secret_file {
+                kanidm_client
+                    .update_person_radius_secret(name, secret_file)
+                    .wrap_err_with(|| format!("while syncing radius secret of person '{name}'"))?;
+            }
+
+            if let Some(unix) = &person.unix {
+                let was_posix = has_class(existing_persons, name, "posixaccount");
+                kanidm_client
+                    .update_unix_attrs(
+                        ENDPOINT_PERSON,
+                        existing_persons,
+                        name,
+                        &json!({ "gidnumber": unix.gid_number }),
+                    )
+                    .wrap_err_with(|| format!("while syncing unix attributes of person '{name}'"))?;
+
+                // The current unix password can't be compared, so it is only set initially unless forced
+                if let Some(password_file) = unix
+                    .unix_password_file
+                    .as_ref()
+                    .filter(|_| !was_posix || unix.force_unix_password_reset)
+                {
+                    let password = std::fs::read_to_string(password_file)
+                        .wrap_err_with(|| format!("failed to read {:?}", password_file))?;
+                    kanidm_client
+                        .set_person_unix_password(name, password.trim())
+                        .wrap_err_with(|| format!("while setting unix password of person '{name}'"))?;
+                }
+            }
+
+            run_hooks(
+                kanidm_client,
+                person.hooks.as_ref(),
+                "person",
+                name,
+                created,
+                changes_before,
+            )?;
+        } else if existing_persons.contains_key(name) {
+            kanidm_client
+                .delete_entity(ENDPOINT_PERSON, name)
+                .wrap_err_with(|| format!("while deleting person '{name}'"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes all scope maps of the given kind for groups that are not declared in the state.
+fn remove_orphaned_scope_maps(
+    kanidm_client: &KanidmClient,
+    existing_oauth2s: &HashMap<String, Value>,
+    name: &str,
+    endpoint_name: &str,
+    attr_name: &str,
+    scope_maps: &HashMap<String, Vec<String>>,
+    preserved_groups: &[String],
+) -> Result<()> {
+    let current_values = get_value_array(&format!("/attrs/{attr_name}"), existing_oauth2s, name)?;
+    let orphaned: Vec<&str> = current_values
+        .iter()
+        .filter_map(|x| x.split_once(": "))
+        .map(|(group, _)| strip_domain(group))
+        .filter(|group| !scope_maps.keys().any(|x| strip_domain(x) == *group))
+        .filter(|group| !preserved_groups.iter().any(|x| strip_domain(x) == *group))
+        .collect();
+
+    for group in orphaned {
+        kanidm_client.update_oauth2_map(endpoint_name, attr_name, existing_oauth2s, name, group, vec![])?;
+    }
+
+    Ok(())
+}
+
+pub fn sync_oauth2s(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    existing_oauth2s: &mut HashMap<String, Value>,
+    preexisting_entity_names: &HashSet<String>,
+    options: &ProvisionOptions,
+    deadline: &Deadline,
+) -> Result<()> {
+    log_status("Syncing oauth2 resource servers");
+    for (i, (name, oauth2)) in state.systems.oauth2.iter().enumerate() {
+        deadline.next_entity(kanidm_client)?;
+        if options.progress {
+            log_progress("oauth2 resource servers", i + 1, state.systems.oauth2.len());
+        }
+        if oauth2.present {
+            let changes_before = kanidm_client.change_count();
+            let mut do_create = false;
+            if let Some(entity) = existing_oauth2s.get(name) {
+                // Ensure that the client is of correct type (basic/public)
+                // otherwise we need to delete and recreate.
+
+                let is_public = match entity.pointer("/attrs/class") {
+                    Some(Value::Array(x)) => x.iter().any(|x| x.as_str() == Some("oauth2_resource_server_public")),
+                    _ => false,
+                };
+
+                if is_public != oauth2.public {
+                    kanidm_client
+                        .delete_entity(ENDPOINT_OAUTH2, name)
+                        .wrap_err_with(|| format!("while recreating oauth2 '{name}' with a different type"))?;
+                    do_create = true;
+                } else if options.force_recreate.contains(name) {
+                    kanidm_client
+                        .delete_entity(ENDPOINT_OAUTH2, name)
+                        .wrap_err_with(|| format!("while force-recreating oauth2 '{name}'"))?;
+                    do_create = true;
+                }
+            } else {
+                if preexisting_entity_names.contains(name) {
+                    bail!("Cannot create oauth2 resource server '{name}' because the name is already in use by another entity!");
+                }
+                do_create = true;
+            }
+
+            // Without origins, kanidm has no valid redirect target for any authorization flow
+            let origin_urls = if oauth2.enabled {
+                oauth2.origin_url.clone().strings()
+            } else {
+                Vec::new()
+            };
+            if !oauth2.enable_localhost_redirects {
+                for origin_url in &origin_urls {
+                    let is_localhost = Url::parse(origin_url)
+                        .is_ok_and(|x| matches!(x.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")));
+                    if is_localhost {
+                        kanidm_client.warn(&format!("origin {origin_url} of {name} points to localhost, but enable_localhost_redirects is not set"));
+                    }
+                }
+            }
+
+            if do_create {
+                let mut attrs = json!({
+                    "name": [name],
+                    "displayname": [oauth2.display_name],
+                });
+                if !origin_urls.is_empty() {
+                    attrs["oauth2_rs_origin"] = json!(origin_urls);
+                }
+                if oauth2.origin_landing.is_some() {
+                    attrs["oauth2_rs_origin_landing"] = json!(oauth2.origin_landings());
+                }
+
+                kanidm_client
+                    .create_entity(
+                        &format!("{ENDPOINT_OAUTH2}/{}", if oauth2.public { "_public" } else { "_basic" }),
+                        name,
+                        &json!({ "attrs": attrs }),
+                    )
+                    .wrap_err_with(|| format!("while creating oauth2 '{name}'"))?;
+                if let Some(entity) = kanidm_client.wait_for_created_entity(ENDPOINT_OAUTH2, name)? {
+                    existing_oauth2s.insert(name.clone(), entity);
+                }
+            }
+
+            if kanidm_client.is_dry_run() && !existing_oauth2s.contains_key(name) {
+                // The oauth2 resource server would have been created, so there is nothing to compare against
+                continue;
+            }
+
+            // An existing description is only removed if explicitly requested
+            if oauth2.description.is_some() || oauth2.clear_description {
+                update_oauth2!(kanidm_client, &existing_oauth2s, &name, [
+                    "description": oauth2.description.clone(),
+                ]);
+            }
+
+            if options.merge_mode.syncs(&oauth2.origin_landing) {
+                kanidm_client
+                    .update_oauth2_attrs(
+                        existing_oauth2s,
+                        name,
+                        "oauth2_rs_origin_landing",
+                        oauth2.origin_landings(),
+                    )
+                    .wrap_err_with(|| format!("while syncing landing url of oauth2 '{name}'"))?;
+            }
+
+            if oauth2.public {
+                if oauth2.disable_pkce() {
+                    kanidm_client.warn(&format!(
+                        "ignoring disabled pkce for public client {name}, public clients always require pkce"
+                    ));
+                }
+                update_oauth2!(kanidm_client, &existing_oauth2s, &name, [
+                    "displayname": Some(oauth2.display_name.clone()),
+                    "oauth2_allow_localhost_redirect": Some(oauth2.enable_localhost_redirects.to_string()),
+                    "oauth2_jwt_legacy_crypto_enable": Some(oauth2.enable_legacy_crypto.to_string()),
+                    "oauth2_prefer_short_username": Some(oauth2.prefer_short_username.to_string()),
+                ]);
+                kanidm_client
+                    .update_oauth2_attrs(existing_oauth2s, name, "oauth2_rs_origin", origin_urls)
+                    .wrap_err_with(|| format!("while syncing origins of oauth2 '{name}'"))?;
+            } else {
+                if oauth2.enable_localhost_redirects {
+                    kanidm_client.warn(&format!(
+                        "ignoring enable_localhost_redirects for non-public client {name}"
+                    ));
+                }
+                update_oauth2!(kanidm_client, &existing_oauth2s, &name, [
+                    "displayname": Some(oauth2.display_name.clone()),
+                    "oauth2_allow_insecure_client_disable_pkce": Some(oauth2.disable_pkce().to_string()),
+                    "oauth2_jwt_legacy_crypto_enable": Some(oauth2.enable_legacy_crypto.to_string()),
+                    "oauth2_prefer_short_username": Some(oauth2.prefer_short_username.to_string()),
+                ]);
+                kanidm_client
+                    .update_oauth2_attrs(existing_oauth2s, name, "oauth2_rs_origin", origin_urls)
+                    .wrap_err_with(|| format!("while syncing origins of oauth2 '{name}'"))?;
+            }
+
+            if let Some(lifetime) = oauth2.access_token_lifetime {
+                kanidm_client
+                    .update_oauth2_attrs_if_supported(
+                        existing_oauth2s,
+                        name,
+                        "oauth2_access_token_lifetime",
+                        vec![lifetime.to_string()],
+                    )
+                    .wrap_err_with(|| format!("while syncing access token lifetime of oauth2 '{name}'"))?;
+            }
+
+            if let Some(lifetime) = oauth2.refresh_token_lifetime {
+                kanidm_client
+                    .update_oauth2_attrs_if_supported(
+                        existing_oauth2s,
+                        name,
+                        "oauth2_refresh_token_lifetime",
+                        vec![lifetime.to_string()],
+                    )
+                    .wrap_err_with(|| format!("while syncing refresh token lifetime of oauth2 '{name}'"))?;
+            }
+
+            // Only touch the attribute if it is in use, so servers without device flow support
+            // don't cause a warning on every run.
+            let has_device_flow = existing_oauth2s
+                .get(name)
+                .is_some_and(|x| x.pointer("/attrs/oauth2_device_flow_enable").is_some());
+            if oauth2.enable_device_flow || has_device_flow {
+                kanidm_client
+                    .update_oauth2_attrs_if_supported(
+                        existing_oauth2s,
+                        name,
+                        "oauth2_device_flow_enable",
+                        vec![oauth2.enable_device_flow.to_string()],
+                    )
+                    .wrap_err_with(|| format!("while syncing device flow of oauth2 '{name}'"))?;
+            }
+
+            if let Some(strict_redirect_uri) = oauth2.strict_redirect_uri {
+                kanidm_client
+                    .update_oauth2_attrs_if_supported(
+                        existing_oauth2s,
+                        name,
+                        "oauth2_strict_redirect_uri",
+                        vec![strict_redirect_uri.to_string()],
+                    )
+                    .wrap_err_with(|| format!("while syncing strict redirect uri of oauth2 '{name}'"))?;
+            }
+
+            for (group, scopes) in &oauth2.scope_maps {
+                kanidm_client
+                    .update_oauth2_map(
+                        "_scopemap",
+                        "oauth2_rs_scope_map",
+                        existing_oauth2s,
+                        name,
+                        group,
+                        scopes.clone(),
+                    )
+                    .wrap_err_with(|| format!("while syncing scope maps for oauth2 '{name}'"))?;
+            }
+
+            for (group, scopes) in &oauth2.supplementary_scope_maps {
+                kanidm_client
+                    .update_oauth2_map(
+                        "_sup_scopemap",
+                        "oauth2_rs_sup_scope_map",
+                        existing_oauth2s,
+                        name,
+                        group,
+                        scopes.clone(),
+                    )
+                    .wrap_err_with(|| format!("while syncing supplementary scope maps for oauth2 '{name}'"))?;
+            }
+
+            if oauth2.remove_orphaned_scope_maps {
+                remove_orphaned_scope_maps(
+                    kanidm_client,
+                    existing_oauth2s,
+                    name,
+                    "_scopemap",
+                    "oauth2_rs_scope_map",
+                    &oauth2.scope_maps,
+                    &oauth2.preserve_unmanaged_scope_maps,
+                )
+                .wrap_err_with(|| format!("while removing orphaned scope maps for oauth2 '{name}'"))?;
+                remove_orphaned_scope_maps(
+                    kanidm_client,
+                    existing_oauth2s,
+                    name,
+                    "_sup_scopemap",
+                    "oauth2_rs_sup_scope_map",
+                    &oauth2.supplementary_scope_maps,
+                    &oauth2.preserve_unmanaged_scope_maps,
+                )
+                .wrap_err_with(|| format!("while removing orphaned supplementary scope maps for oauth2 '{name}'"))?;
+            }
+
+            for (claim, claim_map) in &oauth2.claim_maps {
+                for (group, values) in &claim_map.values_by_group {
+                    kanidm_client
+                        .update_oauth2_claim_map(existing_oauth2s, name, claim, group, values.clone())
+                        .wrap_err_with(|| format!("while syncing claim map {claim} for oauth2 '{name}'"))?;
+                }
+
+                let has_values = claim_map.values_by_group.values().any(|x| !x.is_empty());
+                kanidm_client
+                    .update_oauth2_claim_map_join(existing_oauth2s, name, claim, &claim_map.join_type, has_values)
+                    .wrap_err_with(|| format!("while syncing claim map join of {claim} for oauth2 '{name}'"))?;
+            }
+
+            for (attr, values) in &oauth2.extra_attrs {
+                kanidm_client
+                    .update_oauth2_attrs(existing_oauth2s, name, attr, values.clone())
+                    .wrap_err_with(|| format!("while syncing extra attribute {attr} of oauth2 '{name}'"))?;
+            }
+
+            if oauth2.remove_orphaned_claim_maps {
+                let current_values = get_value_array("/attrs/oauth2_rs_claim_map", existing_oauth2s, name)?;
+                let orphaned: Vec<(&str, &str)> = current_values
+                    .iter()
+                    .map(|x| x.split(':').collect::<Vec<_>>())
+                    .map(|xs| (xs[0], strip_domain(xs[1])))
+                    .filter(|&(claim, group)| match oauth2.claim_maps.get(claim) {
+                        // Remove groups that are no longer listed for a claim that still exists
+                        Some(claim_map) => !claim_map.values_by_group.keys().any(|x| strip_domain(x) == group),
+                        None => true,
+                    })
+                    .collect();
+
+                for (claim, group) in orphaned {
+                    kanidm_client
+                        .update_oauth2_claim_map(existing_oauth2s, name, claim, group, vec![])
+                        .wrap_err_with(|| format!("while removing orphaned claim map {claim} for oauth2 '{name}'"))?;
+                }
+            }
+
+            if let Some((image, mime_type)) = oauth2.image()? {
+                kanidm_client
+                    .update_oauth2_image(name, image, mime_type)
+                    .wrap_err_with(|| format!("while syncing image of oauth2 '{name}'"))?;
+            }
+
+            if let Some(secret) = oauth2.basic_secret(&options.secrets)? {
+                if oauth2.public {
+                    kanidm_client.warn(&format!("ignoring basic secret for public client {name}"));
+                } else {
+                    kanidm_client
+                        .update_oauth2_basic_secret(name, &secret)
+                        .wrap_err_with(|| format!("while syncing basic secret of oauth2 '{name}'"))?;
+                }
+            }
+
+            run_hooks(
+                kanidm_client,
+                oauth2.hooks.as_ref(),
+                "oauth2",
+                name,
+                do_create,
+                changes_before,
+            )?;
+        } else if existing_oauth2s.contains_key(name) {
+            kanidm_client
+                .delete_entity(ENDPOINT_OAUTH2, name)
+                .wrap_err_with(|| format!("while deleting oauth2 '{name}'"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the sync accounts used by external identity sources. A sync token is only issued if
+/// the configured token file doesn't exist yet, so removing the file rotates the token.
+pub fn sync_sync_accounts(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    existing_sync_accounts: &mut HashMap<String, Value>,
+    preexisting_entity_names: &HashSet<String>,
+    options: &ProvisionOptions,
+    deadline: &Deadline,
+) -> Result<()> {
+    if state.sync_accounts.is_empty() {
+        return Ok(());
+    }
+
+    log_status("Syncing sync accounts");
+    for (i, (name, sync_account)) in state.sync_accounts.iter().enumerate() {
+        deadline.next_entity(kanidm_client)?;
+        if options.progress {
+            log_progress("sync accounts", i + 1, state.sync_accounts.len());
+        }
+        if sync_account.present {
+            let changes_before = kanidm_client.change_count();
+            let created = !existing_sync_accounts.contains_key(name);
+            if created {
+                if preexisting_entity_names.contains(name) {
+                    bail!("Cannot create sync account '{name}' because the name is already in use by another entity!");
+                }
+
+                kanidm_client
+                    .create_entity(ENDPOINT_SYNC_ACCOUNT, name, &json!({ "attrs": { "name": [ name ] } }))
+                    .wrap_err_with(|| format!("while creating sync account '{name}'"))?;
+                if let Some(entity) = kanidm_client.wait_for_created_entity(ENDPOINT_SYNC_ACCOUNT, name)? {
+                    existing_sync_accounts.insert(name.clone(), entity);
+                }
+            }
+
+            if kanidm_client.is_dry_run() && !existing_sync_accounts.contains_key(name) {
+                continue;
+            }
+
+            if sync_account.description.is_some() {
+                update_attrs!(kanidm_client, ENDPOINT_SYNC_ACCOUNT, &existing_sync_accounts, &name, [
+                    "description": sync_account.description.clone().map_or_else(Vec::new, |x| vec![x]),
+                ]);
+            }
+
+            if let Some(token_file) = sync_account.token_file.as_ref().filter(|x| !Path::new(x).exists()) {
+                let token = kanidm_client
+                    .generate_sync_token(name)
+                    .wrap_err_with(|| format!("while issuing sync token of sync account '{name}'"))?;
+                // No token is issued in dry-run mode
+                if let Some(token) = token {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(0o600)
+                        .open(token_file)
+                        .wrap_err_with(|| format!("failed to create {:?}", token_file))?;
+                    file.write_all(token.as_bytes())?;
+                }
+            }
+
+            run_hooks(
+                kanidm_client,
+                sync_account.hooks.as_ref(),
+                "sync_account",
+                name,
+                created,
+                changes_before,
+            )?;
+        } else if existing_sync_accounts.contains_key(name) {
+            kanidm_client
+                .delete_entity(ENDPOINT_SYNC_ACCOUNT, name)
+                .wrap_err_with(|| format!("while deleting sync account '{name}'"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The account expiry that is used to disable the anonymous account and soft-deleted persons.
+const DISABLED_ACCOUNT_EXPIRY: &str = "1970-01-01T00:00:00Z";
+
+/// Whether the given account expiry is the one used to disable accounts, see [`DISABLED_ACCOUNT_EXPIRY`].
+fn is_disabled_expiry(value: &str) -> bool {
+    // kanidm may format the timestamp differently, so any expiry at the epoch counts as disabled
+    value.starts_with("1970-01-01")
+}
+
+/// Returns the account expiry that lies the given accountExpireIn duration from now.
+fn account_expiry_in(expire_in: &str) -> Result<String> {
+    let expiry = OffsetDateTime::now_utc()
+        .replace_nanosecond(0)?
+        .checked_add(parse_duration(expire_in)?.try_into()?)
+        .ok_or_else(|| eyre!("accountExpireIn '{expire_in}' is too large"))?;
+    Ok(expiry.format(&Rfc3339)?)
+}
+
+/// Enables or disables the built-in anonymous account. The account is never created, tracked or removed.
+pub fn sync_anonymous(state: &State, kanidm_client: &KanidmClient) -> Result<()> {
+    let Some(anonymous) = &state.anonymous else {
+        return Ok(());
+    };
+
+    log_status("Syncing anonymous account");
+    let existing_service_accounts = kanidm_client.get_entities(ENDPOINT_SERVICE_ACCOUNT)?;
+    let current_expiry = get_value_array("/attrs/account_expire", &existing_service_accounts, "anonymous")?;
+    let is_enabled = !current_expiry.iter().any(|x| is_disabled_expiry(x));
+    if anonymous.enabled == is_enabled {
+        return Ok(());
+    }
+
+    let desired_expiry = if anonymous.enabled {
+        vec![]
+    } else {
+        vec![DISABLED_ACCOUNT_EXPIRY.to_string()]
+    };
+    kanidm_client
+        .update_entity_attrs(
+            ENDPOINT_SERVICE_ACCOUNT,
+            &existing_service_accounts,
+            "anonymous",
+            "account_expire",
+            desired_expiry,
+            false,
+        )
+        .wrap_err("while syncing the anonymous account")
+}
+
+pub fn sync_radius_group(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    existing_groups: &HashMap<String, Value>,
+    radius_group: &str,
+) -> Result<()> {
+    log_status("Syncing radius group members");
+    let current_members: HashSet<String> = get_value_array("/attrs/member", existing_groups, radius_group)?
+        .iter()
+        .map(|x| strip_domain(x).to_string())
+        .collect();
+
+    let missing_members: Vec<String> = state
+        .persons
+        .iter()
+        .filter(|(name, person)| {
+            person.present && person.radius_secret_file.is_some() && !current_members.contains(*name)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    if !missing_members.is_empty() {
+        kanidm_client.update_entity_attrs(
+            ENDPOINT_GROUP,
+            existing_groups,
+            radius_group,
+            "member",
+            missing_members,
+            true,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn setup_provision_tracking(
+    kanidm_client: &KanidmClient,
+    existing_groups: &mut HashMap<String, Value>,
+) -> Result<HashSet<String>> {
+    if !existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        kanidm_client.create_entity(
+            ENDPOINT_GROUP,
+            PROVISION_TRACKING_GROUP,
+            &json!({ "attrs": { "name": [ PROVISION_TRACKING_GROUP ] } }),
+        )?;
+        if let Some(entity) = kanidm_client.wait_for_created_entity(ENDPOINT_GROUP, PROVISION_TRACKING_GROUP)? {
+            existing_groups.insert(PROVISION_TRACKING_GROUP.to_string(), entity);
+        }
+    }
+
+    if kanidm_client.is_dry_run() && !existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        // Nothing can have been provisioned before the tracking group existed
+        return Ok(HashSet::new());
+    }
+
+    let entity = existing_groups.get(PROVISION_TRACKING_GROUP).ok_or_else(|| {
+        eyre!("Could not find provision tracking group '{PROVISION_TRACKING_GROUP}' in {ENDPOINT_GROUP}")
+    })?;
+
+    let mut current_values = match entity.pointer("/attrs/member") {
+        Some(Value::Array(x)) => x
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| strip_domain(x).to_string())
+            .collect(),
+        None => vec![],
+        other => {
+            bail!("Invalid attr value for members of entity {ENDPOINT_GROUP}/{PROVISION_TRACKING_GROUP}: {other:?}")
+        }
+    };
+
+    Ok(HashSet::from_iter(current_values.drain(0..)))
+}
+
+/// Returns the endpoint for the given entity type, as used in `all_tracked_entities`.
+fn entity_endpoint(kind: &str) -> &'static str {
+    match kind {
+        "group" => ENDPOINT_GROUP,
+        "person" => ENDPOINT_PERSON,
+        "oauth2" => ENDPOINT_OAUTH2,
+        "sync_account" => ENDPOINT_SYNC_ACCOUNT,
+        _ => unreachable!("unknown entity kind '{kind}'"),
+    }
+}
+
+/// Returns the name and type of all entities that were provisioned previously, but are no
+/// longer part of the state. Kept entities and builtin accounts are never orphans.
+fn find_orphaned_entities(
+    provisioned_entities: &HashSet<String>,
+    existing_entities: &[(&'static str, &HashMap<String, Value>)],
+    tracked_entities: &[String],
+    kept_entities: &HashSet<String>,
+) -> Vec<(String, &'static str)> {
+    let tracked_entities = HashSet::from_iter(tracked_entities.iter().cloned());
+    let is_protected = |x: &str| kept_entities.contains(x) || BUILTIN_ACCOUNTS.contains(&x);
+    let mut orphans: Vec<(String, &'static str)> = provisioned_entities
+        .difference(&tracked_entities)
+        .filter(|x| !is_protected(x))
+        .filter_map(|orphan| {
+            let (kind, _) = existing_entities.iter().find(|(_, x)| x.contains_key(orphan))?;
+            Some((orphan.clone(), *kind))
+        })
+        .collect();
+    orphans.sort_unstable();
+    orphans
+}
+
+pub fn remove_orphaned_entities(
+    kanidm_client: &KanidmClient,
+    provisioned_entities: &HashSet<String>,
+    existing_entities: &[(&'static str, &HashMap<String, Value>)],
+    tracked_entities: &[String],
+    kept_entities: &HashSet<String>,
+) -> Result<Vec<(String, &'static str)>> {
+    log_status("Removing orphaned entities");
+    // Remove any entities that are no longer provisioned
+    let orphaned_entities =
+        find_orphaned_entities(provisioned_entities, existing_entities, tracked_entities, kept_entities);
+    kanidm_client.confirm_orphan_removal(&orphaned_entities)?;
+    let mut removed_orphans = Vec::new();
+    for (orphan, kind) in orphaned_entities {
+        kanidm_client.delete_entity(entity_endpoint(kind), &orphan)?;
+        removed_orphans.push((orphan, kind));
+    }
+
+    Ok(removed_orphans)
+}
+
+/// Disables orphaned entities on the first run that sees them, and deletes them once they have
+/// been orphaned for longer than the grace period. Persons are disabled by expiring their account
+/// and groups by removing all of their members. Oauth2 resource servers and sync accounts cannot
+/// be disabled, so only their deletion is delayed.
+pub fn soft_delete_orphaned_entities(
+    state: &State,
+    kanidm_client: &KanidmClient,
+    soft_delete: &SoftDelete,
+    orphaned_entities: Vec<(String, &'static str)>,
+    existing_groups: &HashMap<String, Value>,
+    existing_persons: &HashMap<String, Value>,
+) -> Result<Vec<(String, &'static str)>> {
+    log_status("Soft-deleting orphaned entities");
+    let state_file = &soft_delete.state_file;
+    let mut disabled_since: HashMap<String, u64> = match std::fs::read_to_string(state_file) {
+        Ok(content) => serde_json::from_str(&content).wrap_err_with(|| format!("failed to parse {state_file:?}"))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e).wrap_err_with(|| format!("failed to read {state_file:?}")),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // Persons that were added to the state again before their grace period ended are re-enabled.
+    // Only the expiry set when disabling them is replaced, by the declared accountExpireIn if any.
+    let is_orphan = |x: &str| orphaned_entities.iter().any(|(orphan, _)| orphan == x);
+    for name in disabled_since.keys().filter(|x| !is_orphan(x)) {
+        if !existing_persons.contains_key(name) {
+            continue;
+        }
+        let current_expiry = get_value_array("/attrs/account_expire", existing_persons, name)?;
+        if !current_expiry.iter().any(|x| is_disabled_expiry(x)) {
+            continue;
+        }
+
+        let desired_expiry = match state.persons.get(name).and_then(|x| x.account_expire_in.as_ref()) {
+            Some(expire_in) => vec![account_expiry_in(expire_in)?],
+            None => vec![],
+        };
+        kanidm_client
+            .update_entity_attrs(
+                ENDPOINT_PERSON,
+                existing_persons,
+                name,
+                "account_expire",
+                desired_expiry,
+                false,
+            )
+            .wrap_err_with(|| format!("while re-enabling person '{name}'"))?;
+    }
+    disabled_since.retain(|x, _| is_orphan(x));
+
+    let is_expired = |since: u64| now.saturating_sub(since) >= soft_delete.grace_period.as_secs();
+    let expired_orphans: Vec<(String, &'static str)> = orphaned_entities
+        .iter()
+        .filter(|(orphan, _)| disabled_since.get(orphan).is_some_and(|x| is_expired(*x)))
+        .cloned()
+        .collect();
+    kanidm_client.confirm_orphan_removal(&expired_orphans)?;
+
+    let mut removed_orphans = Vec::new();
+    for (orphan, kind) in orphaned_entities {
+        match disabled_since.get(&orphan) {
+            Some(since) if is_expired(*since) => {
+                kanidm_client.delete_entity(entity_endpoint(kind), &orphan)?;
+                disabled_since.remove(&orphan);
+                removed_orphans.push((orphan, kind));
+            }
+            Some(_) => {}
+            None => {
+                match kind {
+                    "group" => kanidm_client
+                        .update_entity_attrs(ENDPOINT_GROUP, existing_groups, &orphan, "member", vec![], false)
+                        .wrap_err_with(|| format!("while disabling group '{orphan}'"))?,
+                    "person" => kanidm_client
+                        .update_entity_attrs(
+                            ENDPOINT_PERSON,
+                            existing_persons,
+                            &orphan,
+                            "account_expire",
+                            vec![DISABLED_ACCOUNT_EXPIRY.to_string()],
+                            false,
+                        )
+                        .wrap_err_with(|| format!("while disabling person '{orphan}'"))?,
+                    _ => log_event(
+                        "Pending",
+                        &format!("{}/{orphan} will be deleted later", entity_endpoint(kind)),
+                    ),
+                }
+                disabled_since.insert(orphan, now);
+            }
+        }
+    }
+
+    if !kanidm_client.is_dry_run() {
+        std::fs::write(state_file, serde_json::to_string_pretty(&disabled_since)?)
+            .wrap_err_with(|| format!("failed to write {state_file:?}"))?;
+    }
+
+    Ok(removed_orphans)
+}
+
+/// Lists the entities that would be removed as orphans by a regular run, together with their
+/// type. Nothing is changed in kanidm, not even the tracking group is created.
+pub fn list_orphans(state: &State, kanidm_client: &KanidmClient) -> Result<Vec<(String, &'static str)>> {
+    let tracked_entities = all_tracked_entities(state)?;
+    let existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+    let existing_sync_accounts = kanidm_client.get_entities(ENDPOINT_SYNC_ACCOUNT)?;
+
+    let provisioned_entities: HashSet<String> = if existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        get_value_array("/attrs/member", &existing_groups, PROVISION_TRACKING_GROUP)?
+            .iter()
+            .map(|x| strip_domain(x).to_string())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    Ok(find_orphaned_entities(
+        &provisioned_entities,
+        &[
+            ("group", &existing_groups),
+            ("person", &existing_persons),
+            ("oauth2", &existing_oauth2s),
+            ("sync_account", &existing_sync_accounts),
+        ],
+        &tracked_entities,
+        &state.kept_entities(),
+    ))
+}
+
+/// Adds all entities declared in the state that already exist in kanidm to the tracking group,
+/// without changing anything else. Used once when migrating an existing instance to this tool,
+/// so that subsequent runs can safely remove orphans.
+pub fn adopt_existing(state: &State, kanidm_client: &KanidmClient) -> Result<Vec<String>> {
+    all_tracked_entities(state)?;
+    let mut existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+    let existing_sync_accounts = kanidm_client.get_entities(ENDPOINT_SYNC_ACCOUNT)?;
+    setup_provision_tracking(kanidm_client, &mut existing_groups)?;
+
+    log_status("Tracking existing entities");
+    let adopted_entities = state.adopted_entities();
+    let groups = state
+        .groups
+        .iter()
+        .filter(|(name, x)| x.present && existing_groups.contains_key(*name));
+    let persons = state
+        .persons
+        .iter()
+        .filter(|(name, x)| x.present && existing_persons.contains_key(*name));
+    let oauth2s = state
+        .systems
+        .oauth2
+        .iter()
+        .filter(|(name, x)| x.present && existing_oauth2s.contains_key(*name));
+    let sync_accounts = state
+        .sync_accounts
+        .iter()
+        .filter(|(name, x)| x.present && existing_sync_accounts.contains_key(*name));
+    let mut entities: Vec<String> = groups
+        .map(|(name, _)| name)
+        .chain(persons.map(|(name, _)| name))
+        .chain(oauth2s.map(|(name, _)| name))
+        .chain(sync_accounts.map(|(name, _)| name))
+        .filter(|x| !adopted_entities.contains(*x))
+        .cloned()
+        .collect();
+    entities.sort_unstable();
+
+    if !kanidm_client.is_dry_run() || existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        kanidm_client.update_entity_attrs(
+            ENDPOINT_GROUP,
+            &existing_groups,
+            PROVISION_TRACKING_GROUP,
+            "member",
+            entities.clone(),
+            true,
+        )?;
+    }
+
+    Ok(entities)
+}
+
+/// Parses the values of an oauth2 scope map as shown by kanidm, e.g. `group@domain: {"openid", "email"}`.
+fn parse_exported_scope_maps(entity: &Value, attr: &str) -> serde_json::Map<String, Value> {
+    let values = entity.pointer(&format!("/attrs/{attr}")).and_then(|x| x.as_array());
+    values
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.as_str()?.split_once(": "))
+        .map(|(group, scopes)| {
+            let scopes: Vec<&str> = scopes
+                .trim_start_matches('{')
+                .trim_end_matches('}')
+                .split(", ")
+                .map(|x| x.trim_matches('"'))
+                .collect();
+            (strip_domain(group).to_string(), json!(scopes))
+        })
+        .collect()
+}
+
+/// Builds a state from the groups, persons and oauth2 resource servers that currently exist in
+/// kanidm. Built-in entities are skipped. Secrets cannot be read back, so the basic secret of
+/// each oauth2 resource server is exported as a placeholder that has to be replaced.
+pub fn export_state(kanidm_client: &KanidmClient) -> Result<Value> {
+    let existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+    let values = |entities: &HashMap<String, Value>, name: &str, attr: &str| {
+        get_value_array(&format!("/attrs/{attr}"), entities, name)
+    };
+    let single = |entities: &HashMap<String, Value>, name: &str, attr: &str| {
+        Ok::<_, color_eyre::Report>(values(entities, name, attr)?.into_iter().next())
+    };
+    let exported = |entities: &HashMap<String, Value>| {
+        let mut names: Vec<String> = entities
+            .iter()
+            .filter(|(name, x)| !is_builtin_entity(x) && *name != PROVISION_TRACKING_GROUP)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort_unstable();
+        names
+    };
+
+    log_status("Exporting groups");
+    let mut groups = serde_json::Map::new();
+    for name in exported(&existing_groups) {
+        let mut group = json!({});
+        if let Some(description) = single(&existing_groups, &name, "description")? {
+            group["description"] = json!(description);
+        }
+        if let Some(filter) = single(&existing_groups, &name, "dyngroup_filter")? {
+            group["memberFilter"] = json!(filter);
+        } else {
+            let mut members: Vec<String> = values(&existing_groups, &name, "member")?
+                .iter()
+                .map(|x| strip_domain(x).to_string())
+                .collect();
+            members.sort_unstable();
+            group["members"] = json!(members);
+        }
+        if has_class(&existing_groups, &name, "posixgroup") {
+            let gid_number = single(&existing_groups, &name, "gidnumber")?.and_then(|x| x.parse::<u32>().ok());
+            group["unix"] = json!({ "gidNumber": gid_number });
+        }
+        groups.insert(name, group);
+    }
+
+    log_status("Exporting persons");
+    let mut persons = serde_json::Map::new();
+    for name in exported(&existing_persons) {
+        let mut person = json!({
+            "displayName": single(&existing_persons, &name, "displayname")?.unwrap_or_default(),
+            "mailAddresses": values(&existing_persons, &name, "mail")?,
+        });
+        if let Some(legal_name) = single(&existing_persons, &name, "legalname")? {
+            person["legalName"] = json!(legal_name);
+        }
+        if has_class(&existing_persons, &name, "posixaccount") {
+            let gid_number = single(&existing_persons, &name, "gidnumber")?.and_then(|x| x.parse::<u32>().ok());
+            person["unix"] = json!({ "gidNumber": gid_number });
+        }
+        persons.insert(name, person);
+    }
+
+    log_status("Exporting oauth2 resource servers");
+    let mut oauth2s = serde_json::Map::new();
+    for name in exported(&existing_oauth2s) {
+        let entity = &existing_oauth2s[&name];
+        let flag = |attr: &str| {
+            Ok::<_, color_eyre::Report>(single(&existing_oauth2s, &name, attr)?.as_deref() == Some("true"))
+        };
+        let mut oauth2 = json!({
+            "displayName": single(&existing_oauth2s, &name, "displayname")?.unwrap_or_default(),
+            "originUrl": values(&existing_oauth2s, &name, "oauth2_rs_origin")?,
+            "enableLocalhostRedirects": flag("oauth2_allow_localhost_redirect")?,
+            "enableLegacyCrypto": flag("oauth2_jwt_legacy_crypto_enable")?,
+            "allowInsecureClientDisablePkce": flag("oauth2_allow_insecure_client_disable_pkce")?,
+            "preferShortUsername": flag("oauth2_prefer_short_username")?,
+            "enableDeviceFlow": flag("oauth2_device_flow_enable")?,
+            "scopeMaps": parse_exported_scope_maps(entity, "oauth2_rs_scope_map"),
+            "supplementaryScopeMaps": parse_exported_scope_maps(entity, "oauth2_rs_sup_scope_map"),
+        });
+        if has_class(&existing_oauth2s, &name, "oauth2_resource_server_public") {
+            oauth2["public"] = json!(true);
+        } else {
+            oauth2["basicSecretFile"] = json!(format!("{EXPORT_PLACEHOLDER}: the basic secret of {name}"));
+        }
+        if let Some(description) = single(&existing_oauth2s, &name, "description")? {
+            oauth2["description"] = json!(description);
+        }
+        if let Some(origin_landing) = single(&existing_oauth2s, &name, "oauth2_rs_origin_landing")? {
+            oauth2["originLanding"] = json!(origin_landing);
+        }
+        if let Some(strict_redirect_uri) = single(&existing_oauth2s, &name, "oauth2_strict_redirect_uri")? {
+            oauth2["strictRedirectUri"] = json!(strict_redirect_uri == "true");
+        }
+        for (attr, key) in [
+            ("oauth2_access_token_lifetime", "accessTokenLifetime"),
+            ("oauth2_refresh_token_lifetime", "refreshTokenLifetime"),
+        ] {
+            if let Some(lifetime) = single(&existing_oauth2s, &name, attr)?.and_then(|x| x.parse::<u64>().ok()) {
+                oauth2[key] = json!(lifetime);
+            }
+        }
+
+        // Claim maps are shown as `claim:group:join:"value1,value2"`
+        let mut claim_maps = serde_json::Map::new();
+        for claim_map in values(&existing_oauth2s, &name, "oauth2_rs_claim_map")? {
+            let [claim, group, join, claim_values] = claim_map.splitn(4, ':').collect::<Vec<_>>()[..] else {
+                continue;
+            };
+            let join_type = match join {
+                " " => "ssv",
+                "," => "csv",
+                _ => "array",
+            };
+            let entry = claim_maps
+                .entry(claim)
+                .or_insert_with(|| json!({ "joinType": join_type, "valuesByGroup": {} }));
+            let claim_values: Vec<&str> = claim_values.trim_matches('"').split(',').collect();
+            entry["valuesByGroup"][strip_domain(group)] = json!(claim_values);
+        }
+        oauth2["claimMaps"] = Value::Object(claim_maps);
+        oauth2s.insert(name, oauth2);
+    }
+
+    Ok(json!({
+        "groups": groups,
+        "persons": persons,
+        "systems": { "oauth2": oauth2s },
+    }))
+}
+
+/// Returns true for entities that ship with kanidm and must never be deleted.
+fn is_builtin_entity(entity: &Value) -> bool {
+    // All builtin entities are created with well-known uuids. Accounts and groups like idm_admin or
+    // idm_admins use 00000000-0000-0000-0000-000000000xxx, system and schema entries use ...-ffffxxxxxxxx.
+    entity
+        .pointer("/attrs/uuid/0")
+        .and_then(|x| x.as_str())
+        .is_some_and(|x| x.starts_with("00000000-0000-0000-0000-"))
+}
+
+/// Deletes all entities listed in the given remove state, regardless of the provision tracking group.
+pub fn remove_entities(state: &State, remove_state: &RemoveState, kanidm_client: &KanidmClient) -> Result<Vec<String>> {
+    log_status("Removing entities from remove state");
+    let existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+
+    let mut to_remove = Vec::new();
+    for (endpoint, existing_entities, names) in [
+        (ENDPOINT_GROUP, &existing_groups, &remove_state.groups),
+        (ENDPOINT_PERSON, &existing_persons, &remove_state.persons),
+        (ENDPOINT_OAUTH2, &existing_oauth2s, &remove_state.oauth2),
+    ] {
+        for name in names {
+            let is_declared = state.groups.get(name).is_some_and(|x| x.present)
+                || state.persons.get(name).is_some_and(|x| x.present)
+                || state.systems.oauth2.get(name).is_some_and(|x| x.present);
+            if is_declared {
+                bail!("Refusing to remove {endpoint}/{name}, it is declared as present in the state");
+            }
+
+            let Some(entity) = existing_entities.get(name) else {
+                kanidm_client.warn(&format!("cannot remove {endpoint}/{name}, it does not exist"));
+                continue;
+            };
+
+            if name == PROVISION_TRACKING_GROUP || is_builtin_entity(entity) {
+                bail!("Refusing to remove {endpoint}/{name}, it is a protected kanidm entity");
+            }
+
+            to_remove.push((endpoint, name));
+        }
+    }
+
+    // Only start deleting after all entities have been checked
+    for &(endpoint, name) in &to_remove {
+        kanidm_client
+            .delete_entity(endpoint, name)
+            .wrap_err_with(|| format!("while removing {endpoint}/{name}"))?;
+    }
+
+    Ok(to_remove.into_iter().map(|(_, name)| name.clone()).collect())
+}
+
+pub fn prune_provision_tracking(kanidm_client: &KanidmClient) -> Result<()> {
+    log_status("Pruning provision tracking group");
+    // Query everything again, so we only ever remove members which are definitely gone
+    // from the server right now. Members are removed individually instead of overwriting
+    // the whole attribute to never lose entries that were added in the meantime.
+    let existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+    let existing_sync_accounts = kanidm_client.get_entities(ENDPOINT_SYNC_ACCOUNT)?;
+
+    if kanidm_client.is_dry_run() && !existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        return Ok(());
+    }
+
+    let stale_members: Vec<String> = get_value_array("/attrs/member", &existing_groups, PROVISION_TRACKING_GROUP)?
+        .iter()
+        .map(|x| strip_domain(x).to_string())
+        .filter(|x| {
+            !existing_groups.contains_key(x)
+                && !existing_persons.contains_key(x)
+                && !existing_oauth2s.contains_key(x)
+                && !existing_sync_accounts.contains_key(x)
+        })
+        .collect();
+
+    if !stale_members.is_empty() {
+        kanidm_client.remove_entity_attr_values(ENDPOINT_GROUP, PROVISION_TRACKING_GROUP, "member", &stale_members)?;
+    }
+
+    Ok(())
+}
+
+/// Applies the given state to kanidm.
+pub fn provision(mut state: State, kanidm_client: &KanidmClient, options: ProvisionOptions) -> Result<ProvisionReport> {
+    // Fail early instead of after some entities have already been changed
+    if state.has_hooks() && !options.allow_hooks {
+        bail!("The state declares hooks, which are only run if explicitly allowed with --allow-hooks");
+    }
+
+    for oauth2 in state.systems.oauth2.values().filter(|x| x.present) {
+        oauth2.basic_secret(&options.secrets)?;
+    }
+
+    for name in &options.force_recreate {
+        if !state.systems.oauth2.get(name).is_some_and(|x| x.present) {
+            bail!("Cannot force-recreate '{name}', it is not declared as a present oauth2 resource server");
+        }
+    }
+
+    if state.uses_domain_placeholder() {
+        let domain = match &options.domain {
+            Some(domain) => domain.clone(),
+            None => kanidm_client
+                .get_domain_name()
+                .wrap_err("while querying the domain to replace {domain} in oauth2 origins")?,
+        };
+        state.expand_domain(&domain);
+    }
+
+    let tracked_entities = all_tracked_entities(&state)?;
+
+    // Retrieve known entities so we can check for duplicates dynamically
+    let mut existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+    let mut existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
+    let mut existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
+    let mut existing_sync_accounts = kanidm_client.get_entities(ENDPOINT_SYNC_ACCOUNT)?;
+
+    let mut preexisting_entity_names = HashSet::new();
+    preexisting_entity_names.extend(existing_groups.keys().cloned());
+    preexisting_entity_names.extend(existing_persons.keys().cloned());
+    preexisting_entity_names.extend(existing_oauth2s.keys().cloned());
+    preexisting_entity_names.extend(existing_sync_accounts.keys().cloned());
+
+    // Create and query a group that contains all (previously) provisioned entities.
+    let provisioned_entities = setup_provision_tracking(kanidm_client, &mut existing_groups)?;
+
+    let deadline = Deadline::new(options.deadline, options.interrupted.clone(), &state);
+    sync_groups(
+        &state,
+        kanidm_client,
+        &mut existing_groups,
+        &preexisting_entity_names,
+        &options,
+        &deadline,
+    )?;
+    sync_persons(
+        &state,
+        kanidm_client,
+        &mut existing_persons,
+        &preexisting_entity_names,
+        &options,
+        &deadline,
+    )?;
+    sync_oauth2s(
+        &state,
+        kanidm_client,
+        &mut existing_oauth2s,
+        &preexisting_entity_names,
+        &options,
+        &deadline,
+    )?;
+    sync_sync_accounts(
+        &state,
+        kanidm_client,
+        &mut existing_sync_accounts,
+        &preexisting_entity_names,
+        &options,
+        &deadline,
+    )?;
+
+    // Sync group members
+    deadline.check(kanidm_client)?;
+    log_status("Syncing group members");
+    for (name, group) in &state.groups {
+        // Members of dynamic groups are maintained by kanidm
+        if group.present && group.member_filter.is_none() && options.merge_mode.syncs(&group.members) {
+            if kanidm_client.is_dry_run() && !existing_groups.contains_key(name) {
+                continue;
+            }
+
+            update_attrs!(kanidm_client, ENDPOINT_GROUP, &existing_groups, &name, [
+                "member": group.members.clone().unwrap_or_else(Vec::new),
+            ]);
+        }
+    }
+
+    // Update entity tracking group now that new entities exist.
+    // Always add to this group's member, and never overwrite so
+    // we can be sure to never lose any entries in case of unexpected errors.
+    // Members can thus only be removed by removing the entity itself.
+    log_status("Tracking provisioned entities");
+    // Update groups now to ensure we catch changes in case an entity removal caused
+    // the previous value to be outdated (e.g. changing oauth2 public to basic could cause that)
+    existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
+
+    if let Some(radius_group) = &options.radius_group {
+        sync_radius_group(&state, kanidm_client, &existing_groups, radius_group)?;
+    }
+
+    sync_anonymous(&state, kanidm_client)?;
+
+    // Adopted entities are never tracked, so they can never become orphans.
+    let adopted_entities = state.adopted_entities();
+    if !kanidm_client.is_dry_run() || existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
+        kanidm_client.update_entity_attrs(
+            ENDPOINT_GROUP,
+            &existing_groups,
+            PROVISION_TRACKING_GROUP,
+            "member",
+            tracked_entities
+                .iter()
+                .filter(|x| !adopted_entities.contains(*x))
+                .cloned()
+                .collect(),
+            true,
+        )?;
+    }
+
+    // Entities that were provisioned before being adopted must be untracked explicitly.
+    let untracked_entities: Vec<String> = provisioned_entities.intersection(&adopted_entities).cloned().collect();
+    if !untracked_entities.is_empty() {
+        kanidm_client.remove_entity_attr_values(
+            ENDPOINT_GROUP,
+            PROVISION_TRACKING_GROUP,
+            "member",
+            &untracked_entities,
+        )?;
+    }
+
+    deadline.check(kanidm_client)?;
+    let mut removed_orphans = Vec::new();
+    if !options.no_auto_remove {
+        // Now, remove the orphaned entities that were in the tracking group but
+        // no longer exist in our state description.
+        let kept_entities = state.kept_entities();
+        let existing_entities = [
+            ("group", &existing_groups),
+            ("person", &existing_persons),
+            ("oauth2", &existing_oauth2s),
+            ("sync_account", &existing_sync_accounts),
+        ];
+        removed_orphans = match &options.soft_delete {
+            Some(soft_delete) => soft_delete_orphaned_entities(
+                &state,
+                kanidm_client,
+                soft_delete,
+                find_orphaned_entities(
+                    &provisioned_entities,
+                    &existing_entities,
+                    &tracked_entities,
+                    &kept_entities,
+                ),
+                &existing_groups,
+                &existing_persons,
+            )?,
+            None => remove_orphaned_entities(
+                kanidm_client,
+                &provisioned_entities,
+                &existing_entities,
+                &tracked_entities,
+                &kept_entities,
+            )?,
+        };
+
+        prune_provision_tracking(kanidm_client)?;
+    }
+
+    let mut removed_entities = Vec::new();
+    if let Some(remove_state) = &options.remove_state {
+        removed_entities = remove_entities(&state, remove_state, kanidm_client)?;
+    }
+
+    Ok(ProvisionReport {
+        changes: kanidm_client.changes(),
+        removed_orphans,
+        removed_entities,
+        warnings: kanidm_client.warnings(),
+    })
+}
@@ -1,47 +1,52 @@
-#![doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md"))]
-
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    ffi::OsString,
+    hash::{Hash, Hasher},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use client::{KanidmClient, ENDPOINT_GROUP, ENDPOINT_OAUTH2, ENDPOINT_PERSON};
-use color_eyre::{
-    eyre::{bail, eyre, Result},
-    owo_colors::OwoColorize,
-    Section,
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use color_eyre::eyre::{bail, Result, WrapErr};
+use serde_json::Value;
+use tokio::signal::unix::{signal, SignalKind};
+
+use kanidm_provision::{
+    adopt_existing,
+    client::{AuthMechanism, ClientOptions, KanidmClient, KanidmVersion, DEFAULT_AUTH_USER},
+    export_state, list_orphans, log_event, log_status, log_warn, provision, set_json_logs,
+    state::{read_secrets_file, PersonCsvColumns, RemoveState, State},
+    validate, MergeMode, ProvisionOptions, SoftDelete,
 };
 
-use serde_json::{json, Value};
-use state::State;
-
-use crate::client::get_value_array;
-
-mod client;
-mod state;
-
-const PROVISION_TRACKING_GROUP: &str = "ext_idm_provisioned_entities";
-
-fn log_status(message: &str) {
-    println!("{}", message.blue().bold());
-}
-
-fn log_event(event: &str, message: &str) {
-    println!("{:>12} {}", event.green().bold(), message);
-}
-
 #[derive(Parser)]
-#[command(version, about)]
+#[command(version, about, args_override_self = true)]
 struct Cli {
-    /// The URL of the kanidm instance
+    /// A JSON or TOML file (ending in .toml) that provides defaults for all other options.
+    /// Its keys are the option names without the leading dashes, e.g. "url" or "dry-run".
+    /// Options given on the command line take precedence.
     #[arg(long)]
-    url: String,
+    config: Option<PathBuf>,
+
+    /// The URL of the kanidm instance
+    #[arg(long, required_unless_present = "validate_only")]
+    url: Option<String>,
 
     /// A JSON file describing the desired target state. Refer to the README for a description of
-    /// the required schema.
+    /// the required schema. Files ending in .toml are parsed as TOML instead.
+    #[arg(long, required_unless_present_any = ["state_url", "export"], conflicts_with = "state_url")]
+    state: Option<PathBuf>,
+
+    /// Fetch the state file over HTTP(S) instead of reading it from --state. It is parsed as
+    /// TOML if the url ends in .toml or the server returns a TOML content type.
+    /// --accept-invalid-certs also applies to this request.
     #[arg(long)]
-    state: PathBuf,
+    state_url: Option<String>,
 
     /// DANGEROUS! Accept invalid TLS certificates, e.g. for testing instances.
     #[arg(long)]
@@ -53,394 +58,520 @@ struct Cli {
     /// that are not found in the state file.
     #[arg(long)]
     no_auto_remove: bool,
-}
 
-/// Return a map of all tracked entities and ensure that their names are unique.
-fn all_tracked_entities(state: &State) -> Result<Vec<String>> {
-    let mut entity_names: HashMap<_, Vec<&str>> = HashMap::new();
-    for i in state.groups.keys() {
-        entity_names.entry(i.to_owned()).or_default().push("group");
-    }
-    for i in state.persons.keys() {
-        entity_names.entry(i.to_owned()).or_default().push("person");
-    }
-    for i in state.systems.oauth2.keys() {
-        entity_names.entry(i.to_owned()).or_default().push("oauth2");
-    }
+    /// Print the current and desired value of every attribute that is changed.
+    #[arg(long)]
+    diff: bool,
 
-    let mut error = eyre!("One or more entities have the same name (see notes)");
-    let mut any_bad = false;
-    for (k, v) in &entity_names {
-        if v.len() > 1 {
-            error = error.note(format!("{k} is used multiple times as {v:?}"));
-            any_bad = true;
-        }
-    }
+    /// A group to which all persons with a radius secret will be added. Existing members
+    /// of this group are never removed.
+    #[arg(long)]
+    radius_group: Option<String>,
 
-    if any_bad {
-        return Err(error);
-    }
+    /// Wait up to the given number of seconds for kanidm to become ready before provisioning.
+    #[arg(long, value_name = "SECONDS")]
+    wait_ready: Option<u64>,
 
-    Ok(entity_names.keys().cloned().collect())
-}
+    /// The maximum delay between two readiness checks while waiting for kanidm. The delay
+    /// starts at one second, doubles after every check and is randomized to avoid
+    /// synchronized polling. Defaults to 16 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    wait_ready_max_interval: Option<u64>,
 
-macro_rules! update_attrs {
-    ($kanidm_client:expr, $endpoint:expr, $existing:expr, $name:expr, [ $( $key:literal : $value:expr ),*, ]) => {
-        $(
-            $kanidm_client.update_entity_attrs($endpoint, $existing, $name, $key, $value, false)?;
-        )*
-    };
-}
+    /// After this many consecutive connection failures while waiting for kanidm, pause
+    /// for the circuit breaker cooldown. 0 disables the circuit breaker. Defaults to 10.
+    #[arg(long)]
+    circuit_breaker_threshold: Option<u32>,
 
-macro_rules! update_oauth2 {
-    ($kanidm_client:expr, $existing:expr, $name:expr, [ $( $key:literal : $value:expr ),*, ]) => {
-        $(
-            if let Some(value) = $value {
-                $kanidm_client.update_oauth2_attrs($existing, $name, $key, vec![value])?;
-            } else {
-                $kanidm_client.update_oauth2_attrs($existing, $name, $key, vec![])?;
-            }
-        )*
-    };
-}
+    /// The number of seconds to pause once the circuit breaker has opened. Defaults to 30.
+    #[arg(long, value_name = "SECONDS")]
+    circuit_breaker_cooldown: Option<u64>,
 
-fn sync_groups(
-    state: &State,
-    kanidm_client: &KanidmClient,
-    existing_groups: &mut HashMap<String, Value>,
-    preexisting_entity_names: &HashSet<String>,
-) -> Result<()> {
-    log_status("Syncing groups");
-    for (name, group) in &state.groups {
-        if group.present {
-            if !existing_groups.contains_key(name) {
-                if preexisting_entity_names.contains(name) {
-                    bail!("Cannot create group '{name}' because the name is already in use by another entity!");
-                }
+    /// Only show what would be changed, without sending any modifying request to kanidm.
+    #[arg(long)]
+    dry_run: bool,
 
-                kanidm_client.create_entity(ENDPOINT_GROUP, name, &json!({ "attrs": { "name": [ name ] } }))?;
-                existing_groups.clear();
-                existing_groups.extend(kanidm_client.get_entities(ENDPOINT_GROUP)?);
-            }
-        } else if existing_groups.contains_key(name) {
-            kanidm_client.delete_entity(ENDPOINT_GROUP, name)?;
-        }
-    }
+    /// Like --dry-run, but exits with code 2 if any changes would be made. Useful to detect
+    /// drift between the state file and kanidm in CI.
+    #[arg(long)]
+    check: bool,
 
-    Ok(())
-}
+    /// Send this value as the Host header instead of the host given in --url, e.g. when
+    /// reaching kanidm through an internal address while it validates the Host against its domain.
+    #[arg(long)]
+    host_header: Option<String>,
 
-fn sync_persons(
-    state: &State,
-    kanidm_client: &KanidmClient,
-    existing_persons: &mut HashMap<String, Value>,
-    preexisting_entity_names: &HashSet<String>,
-) -> Result<()> {
-    log_status("Syncing persons");
-    for (name, person) in &state.persons {
-        if person.present {
-            if !existing_persons.contains_key(name) {
-                if preexisting_entity_names.contains(name) {
-                    bail!("Cannot create person '{name}' because the name is already in use by another entity!");
-                }
+    /// Connect to kanidm through this unix domain socket, e.g. one provided by a local reverse
+    /// proxy, instead of over TCP. --url must then be an http:// url and only provides the Host header.
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
 
-                kanidm_client.create_entity(
-                    ENDPOINT_PERSON,
-                    name,
-                    &json!({ "attrs": {
-                        "name": [ name ],
-                        "displayname": [ person.display_name ]
-                    }}),
-                )?;
-                existing_persons.clear();
-                existing_persons.extend(kanidm_client.get_entities(ENDPOINT_PERSON)?);
-            }
+    /// The mechanism used to authenticate as the auth user.
+    #[arg(long, value_enum, default_value_t)]
+    auth_mechanism: AuthMechanism,
+
+    /// Provision as this account instead of idm_admin, e.g. a dedicated account with only
+    /// the privileges needed for the state file.
+    #[arg(long, default_value = DEFAULT_AUTH_USER)]
+    auth_user: String,
+
+    /// Read the password of the auth user from this file instead of KANIDM_PROVISION_IDM_ADMIN_TOKEN.
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// Refuse all redirects. By default, only redirects to the same origin that keep the
+    /// request method (307 and 308) are followed.
+    #[arg(long)]
+    no_follow_redirects: bool,
+
+    /// Add the persons from this CSV file to the state, e.g. to migrate from another system.
+    /// The first row must contain the headers name, displayName and optionally mailAddresses,
+    /// with multiple addresses separated by ';'. Other columns are ignored.
+    #[arg(long, value_name = "FILE")]
+    import_persons_csv: Option<PathBuf>,
+
+    /// Read a person field from a differently named CSV column, e.g. `displayName=Full Name`.
+    #[arg(long, value_name = "FIELD=HEADER", requires = "import_persons_csv")]
+    csv_column: Vec<String>,
+
+    /// Only parse and validate the state file, without contacting kanidm. Exits with
+    /// an error if the state is invalid.
+    #[arg(long)]
+    validate_only: bool,
+
+    /// A file listing groups, persons and oauth2 resource servers that should be removed,
+    /// even if they were not created by this tool. Built-in kanidm entities are never removed.
+    #[arg(long)]
+    remove_state: Option<PathBuf>,
+
+    /// The domain that replaces {domain} in oauth2 origins. By default, the domain is
+    /// queried from kanidm.
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Wait at least this many milliseconds between two requests to kanidm, to avoid
+    /// overwhelming small instances.
+    #[arg(long, value_name = "MILLISECONDS")]
+    min_request_interval: Option<u64>,
+
+    /// Only add all entities from the state file that already exist in kanidm to the
+    /// tracking group, without changing or removing anything else. Use this once when
+    /// migrating an existing instance, so that later runs can safely remove orphans.
+    #[arg(long)]
+    adopt_existing: bool,
+
+    /// Write the groups, persons and oauth2 resource servers that currently exist in kanidm to the
+    /// given file as a state, without changing anything. Secrets can't be exported, so basic secrets
+    /// are written as placeholders that must be replaced before the state can be used.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["state", "state_url"])]
+    export: Option<PathBuf>,
+
+    /// Only print the entities that would be removed as orphans, without changing anything.
+    /// Useful to check what automatic removal would do before relying on it.
+    #[arg(long)]
+    list_orphans: bool,
+
+    /// Never remove these entities as orphans, in addition to keepOrphans in the state file.
+    /// They stay in the tracking group and are removed once they are no longer kept.
+    #[arg(long, value_name = "NAME", num_args = 1..)]
+    keep_orphan: Vec<String>,
+
+    /// Instead of deleting orphaned entities right away, first disable them and only delete
+    /// them on a later run once the grace period has passed. The given file records when each
+    /// orphan was disabled and must be kept between runs.
+    #[arg(long, value_name = "FILE")]
+    soft_delete: Option<PathBuf>,
+
+    /// The number of seconds an orphan stays disabled before --soft-delete removes it.
+    #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 60 * 60, requires = "soft_delete")]
+    soft_delete_grace: u64,
+
+    /// Skip the run if the state, the files it references and all options are unchanged since
+    /// the last successful run, which is recorded in the given file. Changes made directly in
+    /// kanidm are then not detected until the state changes, so this trades correctness for
+    /// speed, e.g. in frequent reconciliation loops.
+    #[arg(long, value_name = "FILE")]
+    skip_if_unchanged: Option<PathBuf>,
+
+    /// Abort cleanly if provisioning has not finished after this many seconds. The deadline
+    /// is checked between two entities, so no entity is left half-way updated.
+    #[arg(long, value_name = "SECONDS")]
+    deadline: Option<u64>,
+
+    /// Delete and recreate the given oauth2 resource servers to reset all of their attributes.
+    /// They are provisioned exactly like newly created ones afterwards.
+    #[arg(long, value_name = "NAME", num_args = 1..)]
+    force_recreate: Vec<String>,
+
+    /// A JSON file containing an object that maps keys to secrets. Oauth2 resource servers
+    /// can reference a secret from this file via basicSecretKey.
+    #[arg(long)]
+    secrets_file: Option<PathBuf>,
+
+    /// Resolve relative paths of secret files in the state, like basicSecretFile or
+    /// radiusSecretFile, against this directory instead of the working directory.
+    #[arg(long)]
+    secrets_dir: Option<PathBuf>,
+
+    /// Resolve relative imageFile paths in the state against this directory instead of
+    /// the working directory.
+    #[arg(long)]
+    assets_dir: Option<PathBuf>,
+
+    /// Allow changing the uid or gid number of existing posix entities. By default this is
+    /// refused, because it breaks the ownership of existing files.
+    #[arg(long)]
+    allow_id_changes: bool,
+
+    /// What happens to optional attributes that are not given in the state file, like the
+    /// legal name of a person or the members of a group. `replace` removes them from kanidm,
+    /// `merge` leaves any existing value untouched.
+    #[arg(long, value_enum, default_value_t)]
+    merge_mode: MergeMode,
+
+    /// Show the raw response body of failed requests in addition to the error reported by kanidm.
+    #[arg(long)]
+    verbose: bool,
+
+    /// Ask for confirmation before removing more than this many members from a single group.
+    /// Without a terminal, such changes are refused unless --yes is given.
+    #[arg(long, value_name = "THRESHOLD")]
+    confirm_large_changes: Option<usize>,
+
+    /// Confirm all large changes and orphan removals without asking.
+    #[arg(long)]
+    yes: bool,
 
-            update_attrs!(kanidm_client, ENDPOINT_PERSON, &existing_persons, &name, [
-                "displayname": vec![person.display_name.clone()],
-                "legalname": person.legal_name.clone().map_or_else(Vec::new, |x| vec![x]),
-                "mail": person.mail_addresses.clone().unwrap_or_else(Vec::new),
-            ]);
-        } else if existing_persons.contains_key(name) {
-            kanidm_client.delete_entity(ENDPOINT_PERSON, name)?;
+    /// List orphaned entities and ask for confirmation before deleting them, e.g. to catch a
+    /// wrong --state file when running by hand. Without a terminal, orphans are only deleted
+    /// if --yes is given. With --soft-delete, only the deletion of orphans whose grace period
+    /// has passed is confirmed. Has no effect with --no-auto-remove.
+    #[arg(long)]
+    interactive: bool,
+
+    /// Assume that kanidm runs this version (e.g. 1.4.2) instead of reading it from the server.
+    /// The version determines which attributes are skipped because the server doesn't support them.
+    #[arg(long, value_name = "VERSION")]
+    assume_version: Option<KanidmVersion>,
+
+    /// Print the number of processed entities while syncing. Enabled by default if stdout is a terminal.
+    #[arg(long)]
+    progress: bool,
+
+    /// Run the postCreate and postUpdate hooks declared in the state. They execute arbitrary
+    /// shell commands, so provisioning fails if the state declares hooks without this flag.
+    #[arg(long)]
+    allow_hooks: bool,
+
+    /// Print one JSON object per line instead of colored text, e.g. for log pipelines.
+    #[arg(long)]
+    json_logs: bool,
+
+    /// Emit all output as tracing events, with every request to kanidm as a span.
+    #[cfg(feature = "tracing")]
+    #[arg(long)]
+    tracing: bool,
+}
+
+/// Returns the file given via --config, which has to be known before the remaining arguments are parsed.
+fn find_config_arg(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|x| x.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
         }
     }
 
-    Ok(())
+    None
 }
 
-fn sync_oauth2s(
-    state: &State,
-    kanidm_client: &KanidmClient,
-    existing_oauth2s: &mut HashMap<String, Value>,
-    preexisting_entity_names: &HashSet<String>,
-) -> Result<()> {
-    log_status("Syncing oauth2 resource servers");
-    for (name, oauth2) in &state.systems.oauth2 {
-        if oauth2.present {
-            let mut do_create = false;
-            if let Some(entity) = existing_oauth2s.get(name) {
-                // Ensure that the client is of correct type (basic/public)
-                // otherwise we need to delete and recreate.
-
-                let is_public = match entity.pointer("/attrs/class") {
-                    Some(Value::Array(x)) => x.iter().any(|x| x.as_str() == Some("oauth2_resource_server_public")),
-                    _ => false,
-                };
-
-                if is_public != oauth2.public {
-                    kanidm_client.delete_entity(ENDPOINT_OAUTH2, name)?;
-                    do_create = true;
-                }
-            } else {
-                if preexisting_entity_names.contains(name) {
-                    bail!("Cannot create oauth2 resource server '{name}' because the name is already in use by another entity!");
-                }
-                do_create = true;
-            }
+/// Converts the options in a config file into the equivalent command line arguments.
+/// Problems are reported like invalid command line arguments, since the error output
+/// can only be configured once all options are known.
+fn config_args(path: &Path) -> Result<Vec<OsString>, clap::Error> {
+    let mut command = Cli::command();
+    let config: Result<BTreeMap<String, Value>, String> = match std::fs::read_to_string(path) {
+        Ok(content) if path.extension().is_some_and(|x| x == "toml") => {
+            toml::from_str(&content).map_err(|e| e.to_string())
+        }
+        Ok(content) => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    let config = config.map_err(|e| {
+        command.error(
+            ErrorKind::InvalidValue,
+            format!("Invalid config file {}: {e}", path.display()),
+        )
+    })?;
 
-            let origin_urls = oauth2.origin_url.clone().strings();
-
-            if do_create {
-                kanidm_client.create_entity(
-                    &format!("{ENDPOINT_OAUTH2}/{}", if oauth2.public { "_public" } else { "_basic" }),
-                    name,
-                    &json!({ "attrs": {
-                        "name": [name],
-                        "oauth2_rs_origin": origin_urls,
-                        "oauth2_rs_origin_landing": [oauth2.origin_landing],
-                        "displayname": [oauth2.display_name],
-                    }}),
-                )?;
-                existing_oauth2s.clear();
-                existing_oauth2s.extend(kanidm_client.get_entities(ENDPOINT_OAUTH2)?);
-            }
+    let mut args = Vec::new();
+    for (key, value) in config {
+        if key == "config" || !command.get_arguments().any(|x| x.get_long() == Some(&key)) {
+            return Err(command.error(
+                ErrorKind::UnknownArgument,
+                format!("Unknown option '{key}' in config file {}", path.display()),
+            ));
+        }
 
-            if oauth2.public {
-                if oauth2.allow_insecure_client_disable_pkce {
-                    println!(
-                        "{}",
-                        format!("WARN: ignoring allow_insecure_client_disable_pkce for public client {name}")
-                            .yellow()
-                            .bold()
-                    );
+        let flag = OsString::from(format!("--{key}"));
+        let values = match value {
+            Value::Array(xs) => xs,
+            x => vec![x],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(flag.clone()),
+                Value::Bool(false) | Value::Null => {}
+                Value::String(x) => args.extend([flag.clone(), x.into()]),
+                Value::Number(x) => args.extend([flag.clone(), x.to_string().into()]),
+                _ => {
+                    return Err(command.error(
+                        ErrorKind::InvalidValue,
+                        format!("Invalid value of option '{key}' in config file {}", path.display()),
+                    ))
                 }
-                update_oauth2!(kanidm_client, &existing_oauth2s, &name, [
-                    "displayname": Some(oauth2.display_name.clone()),
-                    "oauth2_rs_origin_landing": Some(oauth2.origin_landing.clone()),
-                    "oauth2_allow_localhost_redirect": Some(oauth2.enable_localhost_redirects.to_string()),
-                    "oauth2_jwt_legacy_crypto_enable": Some(oauth2.enable_legacy_crypto.to_string()),
-                    "oauth2_prefer_short_username": Some(oauth2.prefer_short_username.to_string()),
-                ]);
-                kanidm_client.update_oauth2_attrs(existing_oauth2s, name, "oauth2_rs_origin", origin_urls)?;
-            } else {
-                if oauth2.enable_localhost_redirects {
-                    println!(
-                        "{}",
-                        format!("WARN: ignoring enable_localhost_redirects for non-public client {name}")
-                            .yellow()
-                            .bold()
-                    );
-                }
-                update_oauth2!(kanidm_client, &existing_oauth2s, &name, [
-                    "displayname": Some(oauth2.display_name.clone()),
-                    "oauth2_rs_origin_landing": Some(oauth2.origin_landing.clone()),
-                    "oauth2_allow_insecure_client_disable_pkce": Some(oauth2.allow_insecure_client_disable_pkce.to_string()),
-                    "oauth2_jwt_legacy_crypto_enable": Some(oauth2.enable_legacy_crypto.to_string()),
-                    "oauth2_prefer_short_username": Some(oauth2.prefer_short_username.to_string()),
-                ]);
-                kanidm_client.update_oauth2_attrs(existing_oauth2s, name, "oauth2_rs_origin", origin_urls)?;
-            }
-
-            for (group, scopes) in &oauth2.scope_maps {
-                kanidm_client.update_oauth2_map(
-                    "_scopemap",
-                    "oauth2_rs_scope_map",
-                    existing_oauth2s,
-                    name,
-                    group,
-                    scopes.clone(),
-                )?;
             }
+        }
+    }
 
-            for (group, scopes) in &oauth2.supplementary_scope_maps {
-                kanidm_client.update_oauth2_map(
-                    "_sup_scopemap",
-                    "oauth2_rs_sup_scope_map",
-                    existing_oauth2s,
-                    name,
-                    group,
-                    scopes.clone(),
-                )?;
-            }
+    Ok(args)
+}
 
-            for (claim, claim_map) in &oauth2.claim_maps {
-                for (group, values) in &claim_map.values_by_group {
-                    kanidm_client.update_oauth2_claim_map(existing_oauth2s, name, claim, group, values.clone())?;
-                }
+/// Identifies a run by everything that influences its outcome, except for the current content of kanidm.
+/// The hash only has to be stable between runs of the same binary.
+fn run_fingerprint(
+    argv: &[OsString],
+    state: &State,
+    remove_state: Option<&RemoveState>,
+    secrets: &HashMap<String, String>,
+) -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    argv.hash(&mut hasher);
+    state.fingerprint(&mut hasher)?;
+    serde_json::to_string(&remove_state)?.hash(&mut hasher);
+    secrets.iter().collect::<BTreeMap<_, _>>().hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
 
-                kanidm_client.update_oauth2_claim_map_join(existing_oauth2s, name, claim, &claim_map.join_type)?;
-            }
+/// Sets the returned flag on the first SIGINT or SIGTERM, so that provisioning stops at the
+/// next entity boundary. A second signal exits immediately.
+fn install_signal_handler() -> Result<Arc<AtomicBool>> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build()?;
+    let (mut sigint, mut sigterm) = {
+        let _guard = runtime.enter();
+        (signal(SignalKind::interrupt())?, signal(SignalKind::terminate())?)
+    };
 
-            if oauth2.remove_orphaned_claim_maps {
-                let current_values = get_value_array("/attrs/oauth2_rs_claim_map", existing_oauth2s, name)?;
-                let orphaned: Vec<(&str, &str)> = current_values
-                    .iter()
-                    .map(|x| x.split(':').collect::<Vec<_>>())
-                    .map(|xs| (xs[0], xs[1].split_once('@').map(|x| x.0).unwrap_or(xs[1])))
-                    .filter(|&(claim, _)| !oauth2.claim_maps.contains_key(claim))
-                    .collect();
-
-                for (claim, group) in orphaned {
-                    kanidm_client.update_oauth2_claim_map(existing_oauth2s, name, claim, group, vec![])?;
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    std::thread::spawn(move || {
+        runtime.block_on(async move {
+            loop {
+                // Mirror the exit code a shell reports for a process killed by the signal
+                let exit_code = tokio::select! {
+                    _ = sigint.recv() => 130,
+                    _ = sigterm.recv() => 143,
+                };
+                if flag.swap(true, Ordering::SeqCst) {
+                    std::process::exit(exit_code);
                 }
+                log_warn("Stopping after the current entity, send the signal again to exit immediately");
             }
+        })
+    });
 
-            if let Some(secret_file) = &oauth2.basic_secret_file {
-                if oauth2.public {
-                    println!(
-                        "{}",
-                        format!("WARN: ignoring basic_secret_file for public client {name}")
-                            .yellow()
-                            .bold()
-                    );
-                } else {
-                    kanidm_client.update_oauth2_basic_secret(name, secret_file)?;
-                }
-            }
-        } else if existing_oauth2s.contains_key(name) {
-            kanidm_client.delete_entity(ENDPOINT_OAUTH2, name)?;
-        }
-    }
+    Ok(interrupted)
+}
 
-    Ok(())
+/// Connects to kanidm and authenticates as the configured user.
+fn connect(args: &Cli) -> Result<KanidmClient> {
+    let Some(url) = args.url.as_deref() else {
+        bail!("--url is required to connect to kanidm");
+    };
+    KanidmClient::new(
+        url,
+        &ClientOptions {
+            accept_invalid_certs: args.accept_invalid_certs,
+            diff: args.diff,
+            dry_run: args.dry_run || args.check,
+            wait_ready: args.wait_ready.map(Duration::from_secs),
+            wait_ready_max_interval: args.wait_ready_max_interval.map(Duration::from_secs),
+            circuit_breaker_threshold: args.circuit_breaker_threshold,
+            circuit_breaker_cooldown: args.circuit_breaker_cooldown.map(Duration::from_secs),
+            host_header: args.host_header.clone(),
+            auth_mechanism: args.auth_mechanism,
+            auth_user: Some(args.auth_user.clone()),
+            token_file: args.token_file.clone(),
+            no_follow_redirects: args.no_follow_redirects,
+            min_request_interval: args.min_request_interval.map(Duration::from_millis),
+            allow_id_changes: args.allow_id_changes,
+            verbose: args.verbose,
+            confirm_large_changes: args.confirm_large_changes,
+            yes: args.yes,
+            interactive: args.interactive,
+            assume_version: args.assume_version,
+            unix_socket: args.unix_socket.clone(),
+        },
+    )
 }
 
-fn setup_provision_tracking(
-    kanidm_client: &KanidmClient,
-    existing_groups: &mut HashMap<String, Value>,
-) -> Result<HashSet<String>> {
-    if !existing_groups.contains_key(PROVISION_TRACKING_GROUP) {
-        kanidm_client.create_entity(
-            ENDPOINT_GROUP,
-            PROVISION_TRACKING_GROUP,
-            &json!({ "attrs": { "name": [ PROVISION_TRACKING_GROUP ] } }),
-        )?;
-        existing_groups.clear();
-        existing_groups.extend(kanidm_client.get_entities(ENDPOINT_GROUP)?);
+fn main() -> Result<()> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    if let Some(config) = find_config_arg(&argv) {
+        let config = config_args(&config).unwrap_or_else(|e| e.exit());
+        // The config file comes first, so that command line arguments override its single-valued
+        // options, while values of multi-valued options are appended to the ones from the file
+        argv.splice(1..1, config);
+    }
+    let args = Cli::parse_from(&argv);
+    if args.json_logs {
+        // Errors are printed without colors, so they don't garble the log pipeline
+        color_eyre::config::HookBuilder::blank().install()?;
+    } else {
+        color_eyre::install()?;
+    }
+    set_json_logs(args.json_logs);
+    #[cfg(feature = "tracing")]
+    if args.tracing {
+        tracing_subscriber::fmt().init();
+    }
+    if let Some(file) = &args.export {
+        let exported = export_state(&connect(&args)?)?;
+        std::fs::write(file, serde_json::to_string_pretty(&exported)? + "\n")
+            .wrap_err_with(|| format!("Failed to write the exported state to {}", file.display()))?;
+        log_status(&format!("Exported the current state to {}", file.display()));
+        return Ok(());
     }
 
-    let entity = existing_groups.get(PROVISION_TRACKING_GROUP).ok_or_else(|| {
-        eyre!("Could not find provision tracking group '{PROVISION_TRACKING_GROUP}' in {ENDPOINT_GROUP}")
-    })?;
-
-    let mut current_values = match entity.pointer("/attrs/member") {
-        Some(Value::Array(x)) => x
-            .iter()
-            .filter_map(|x| x.as_str())
-            .map(|x| x.split_once('@').map(|x| x.0).unwrap_or(x).to_string())
-            .collect(),
-        None => vec![],
-        other => {
-            bail!("Invalid attr value for members of entity {ENDPOINT_GROUP}/{PROVISION_TRACKING_GROUP}: {other:?}")
-        }
+    let deadline = args.deadline.map(|x| Instant::now() + Duration::from_secs(x));
+    let mut state = match (&args.state, &args.state_url) {
+        (Some(state), _) => State::new(state)?,
+        (None, Some(state_url)) => State::from_url(state_url, args.accept_invalid_certs)?,
+        (None, None) => unreachable!("clap requires either --state or --state-url"),
     };
+    state.resolve_relative_paths(args.secrets_dir.as_deref(), args.assets_dir.as_deref());
+    state.keep_orphans.extend(args.keep_orphan.iter().cloned());
+    if let Some(csv_file) = &args.import_persons_csv {
+        let mut columns = PersonCsvColumns::default();
+        for column in &args.csv_column {
+            let Some((field, header)) = column.split_once('=') else {
+                bail!("--csv-column must be of the form FIELD=HEADER, got '{column}'");
+            };
+            columns.set(field, header)?;
+        }
 
-    Ok(HashSet::from_iter(current_values.drain(0..)))
-}
+        let count = state.import_persons_csv(csv_file, &columns)?;
+        log_status(&format!("Imported {count} persons from {}", csv_file.display()));
+    }
+    let remove_state = args.remove_state.as_ref().map(RemoveState::new).transpose()?;
+    let secrets = args
+        .secrets_file
+        .as_ref()
+        .map(read_secrets_file)
+        .transpose()?
+        .unwrap_or_default();
+    if args.validate_only {
+        validate(&state)?;
+        log_status("State is valid");
+        return Ok(());
+    }
 
-fn remove_orphaned_entities(
-    kanidm_client: &KanidmClient,
-    provisioned_entities: &HashSet<String>,
-    existing_groups: &HashMap<String, Value>,
-    existing_persons: &HashMap<String, Value>,
-    existing_oauth2s: &HashMap<String, Value>,
-    tracked_entities: &[String],
-) -> Result<()> {
-    log_status("Removing orphaned entities");
-    // Remove any entities that are no longer provisioned
-    let tracked_entities = HashSet::from_iter(tracked_entities.iter().cloned());
-    let orphaned_entities = provisioned_entities.difference(&tracked_entities);
-    for orphan in orphaned_entities {
-        if existing_groups.contains_key(orphan) {
-            kanidm_client.delete_entity(ENDPOINT_GROUP, orphan)?;
-        } else if existing_persons.contains_key(orphan) {
-            kanidm_client.delete_entity(ENDPOINT_PERSON, orphan)?;
-        } else if existing_oauth2s.contains_key(orphan) {
-            kanidm_client.delete_entity(ENDPOINT_OAUTH2, orphan)?;
+    let fingerprint = args
+        .skip_if_unchanged
+        .as_ref()
+        .map(|_| run_fingerprint(&argv, &state, remove_state.as_ref(), &secrets))
+        .transpose()?;
+    if let (Some(file), Some(fingerprint)) = (&args.skip_if_unchanged, &fingerprint) {
+        let previous = std::fs::read_to_string(file).unwrap_or_default();
+        if previous.trim() == fingerprint {
+            log_status("Nothing changed since the last successful run, skipping");
+            return Ok(());
         }
     }
 
-    Ok(())
-}
+    let kanidm_client = connect(&args)?;
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
-    let args = Cli::parse();
-    let state = State::new(args.state)?;
-    let tracked_entities = all_tracked_entities(&state)?;
-    let kanidm_client = KanidmClient::new(&args.url, args.accept_invalid_certs)?;
-
-    // Retrieve known entities so we can check for duplicates dynamically
-    let mut existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
-    let mut existing_persons = kanidm_client.get_entities(ENDPOINT_PERSON)?;
-    let mut existing_oauth2s = kanidm_client.get_entities(ENDPOINT_OAUTH2)?;
-
-    let mut preexisting_entity_names = HashSet::new();
-    preexisting_entity_names.extend(existing_groups.keys().cloned());
-    preexisting_entity_names.extend(existing_persons.keys().cloned());
-    preexisting_entity_names.extend(existing_oauth2s.keys().cloned());
-
-    // Create and query a group that contains all (previously) provisioned entities.
-    let provisioned_entities = setup_provision_tracking(&kanidm_client, &mut existing_groups)?;
-
-    sync_groups(&state, &kanidm_client, &mut existing_groups, &preexisting_entity_names)?;
-    sync_persons(&state, &kanidm_client, &mut existing_persons, &preexisting_entity_names)?;
-    sync_oauth2s(
-        &state,
+    if args.adopt_existing {
+        let adopted = adopt_existing(&state, &kanidm_client)?;
+        log_status(&format!("Tracking {} existing entities", adopted.len()));
+        return Ok(());
+    }
+
+    if args.list_orphans {
+        let orphans = list_orphans(&state, &kanidm_client)?;
+        log_status(&format!("Found {} orphaned entities", orphans.len()));
+        for (name, kind) in &orphans {
+            log_event("Orphan", &format!("{kind} {name}"));
+        }
+        return Ok(());
+    }
+
+    let report = provision(
+        state,
         &kanidm_client,
-        &mut existing_oauth2s,
-        &mut preexisting_entity_names,
+        ProvisionOptions {
+            no_auto_remove: args.no_auto_remove,
+            radius_group: args.radius_group,
+            remove_state,
+            domain: args.domain,
+            deadline,
+            interrupted: Some(install_signal_handler()?),
+            force_recreate: args.force_recreate,
+            secrets,
+            merge_mode: args.merge_mode,
+            progress: args.progress || std::io::stdout().is_terminal(),
+            allow_hooks: args.allow_hooks,
+            soft_delete: args.soft_delete.map(|state_file| SoftDelete {
+                state_file,
+                grace_period: Duration::from_secs(args.soft_delete_grace),
+            }),
+        },
     )?;
 
-    // Sync group members
-    log_status("Syncing group members");
-    for (name, group) in &state.groups {
-        if group.present {
-            update_attrs!(kanidm_client, ENDPOINT_GROUP, &existing_groups, &name, [
-                "member": group.members.clone(),
-            ]);
+    if let (Some(file), Some(fingerprint)) = (&args.skip_if_unchanged, &fingerprint) {
+        if !kanidm_client.is_dry_run() {
+            std::fs::write(file, fingerprint)
+                .wrap_err_with(|| format!("Failed to record the successful run in {}", file.display()))?;
         }
     }
 
-    // Update entity tracking group now that new entities exist.
-    // Always add to this group's member, and never overwrite so
-    // we can be sure to never lose any entries in case of unexpected errors.
-    // Members can thus only be removed by removing the entity itself.
-    log_status("Tracking provisioned entities");
-    // Update groups now to ensure we catch changes in case an entity removal caused
-    // the previous value to be outdated (e.g. changing oauth2 public to basic could cause that)
-    existing_groups = kanidm_client.get_entities(ENDPOINT_GROUP)?;
-    kanidm_client.update_entity_attrs(
-        ENDPOINT_GROUP,
-        &existing_groups,
-        PROVISION_TRACKING_GROUP,
-        "member",
-        tracked_entities.clone(),
-        true,
-    )?;
+    if !report.warnings.is_empty() {
+        log_status("Warnings");
+        for warning in &report.warnings {
+            log_warn(warning);
+        }
+    }
+
+    if kanidm_client.is_dry_run() {
+        log_status(&format!(
+            "Dry run: {} change(s) would have been applied",
+            report.changes.len()
+        ));
+        for change in &report.changes {
+            log_event("Skipped", change);
+        }
 
-    if !args.no_auto_remove {
-        // Now, remove the orphaned entities that were in the tracking group but
-        // no longer exist in our state description.
-        remove_orphaned_entities(
-            &kanidm_client,
-            &provisioned_entities,
-            &existing_groups,
-            &existing_persons,
-            &existing_oauth2s,
-            &tracked_entities,
-        )?;
+        // Deletions can't be undone, so they are listed separately
+        if !report.removed_orphans.is_empty() {
+            log_status("Would remove orphans");
+            for (name, kind) in &report.removed_orphans {
+                log_event("Orphan", &format!("{kind} {name}"));
+            }
+        }
+        if !report.removed_entities.is_empty() {
+            log_status("Would remove entities from the remove state");
+            for name in &report.removed_entities {
+                log_event("Remove", name);
+            }
+        }
+
+        if args.check && !report.changes.is_empty() {
+            std::process::exit(2);
+        }
     }
 
     Ok(())
@@ -1,35 +1,264 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::Path;
+use std::time::Duration;
 
-use color_eyre::eyre::{Context, Result};
-use serde::Deserialize;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use color_eyre::eyre::{bail, eyre, Context, Result};
+use reqwest::{blocking::Client, header::CONTENT_TYPE};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
 
-#[derive(Debug, Deserialize)]
+use crate::client::strip_domain;
+
+/// Attributes of groups that are managed by dedicated options
+/// and must not be set via `extraAttrs`.
+pub const GROUP_MANAGED_ATTRS: &[&str] = &["class", "name", "description", "member", "dyngroup_filter", "gidnumber"];
+
+/// Attributes of persons that are managed by dedicated options
+/// and must not be set via `extraAttrs`.
+pub const PERSON_MANAGED_ATTRS: &[&str] = &[
+    "class",
+    "name",
+    "displayname",
+    "legalname",
+    "mail",
+    "radius_secret",
+    "gidnumber",
+];
+
+/// Built-in kanidm accounts that may be declared as persons to manage their display name and mail addresses.
+/// They are never created, tracked or removed by this tool.
+pub const BUILTIN_ACCOUNTS: &[&str] = &["admin", "idm_admin"];
+
+/// Attributes of oauth2 resource servers that are managed by dedicated options
+/// and must not be set via `extraAttrs`.
+pub const OAUTH2_MANAGED_ATTRS: &[&str] = &[
+    "class",
+    "name",
+    "displayname",
+    "description",
+    "oauth2_rs_origin",
+    "oauth2_rs_origin_landing",
+    "oauth2_rs_basic_secret",
+    "oauth2_allow_localhost_redirect",
+    "oauth2_jwt_legacy_crypto_enable",
+    "oauth2_allow_insecure_client_disable_pkce",
+    "oauth2_prefer_short_username",
+    "oauth2_device_flow_enable",
+    "oauth2_strict_redirect_uri",
+    "oauth2_access_token_lifetime",
+    "oauth2_refresh_token_lifetime",
+    "oauth2_rs_scope_map",
+    "oauth2_rs_sup_scope_map",
+    "oauth2_rs_claim_map",
+];
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Group {
     #[serde(default = "default_true")]
     pub present: bool,
-    pub members: Vec<String>,
+    pub present_if: Option<String>,
+    #[serde(default = "default_false")]
+    pub adopt: bool,
+    pub description: Option<String>,
+    #[serde(default = "default_false")]
+    pub clear_description: bool,
+    /// Absent if neither members, membersFile nor memberOf provide any members, in which case
+    /// the members are left untouched with --merge-mode merge.
+    pub members: Option<Vec<String>>,
+    pub members_file: Option<String>,
+    /// A kanidm filter that replaces the static member list, making this a dynamic group.
+    pub member_filter: Option<String>,
+    pub unix: Option<GroupUnix>,
+    #[serde(default = "default_false")]
+    pub sudo: bool,
+    pub account_policy: Option<AccountPolicy>,
+    pub hooks: Option<Hooks>,
+    #[serde(default)]
+    pub extra_attrs: HashMap<String, Vec<String>>,
+}
+
+/// The minimum password length kanidm accepts in an account policy.
+const MIN_PASSWORD_LENGTH: u32 = 10;
+
+/// Attributes of groups that are managed by the account policy options.
+pub const ACCOUNT_POLICY_ATTRS: &[&str] = &[
+    "authsession_expiry",
+    "privilege_expiry",
+    "auth_password_minimum_length",
+    "credential_type_minimum",
+    "webauthn_attestation_ca_list",
+    "allow_primary_cred_fallback",
+    "limit_search_max_results",
+    "limit_search_max_filter_test",
+];
+
+/// Account policy settings that apply to all members of a group. Settings that are not
+/// given are left untouched.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountPolicy {
+    /// Maximum lifetime of an authenticated session in seconds.
+    pub auth_session_expiry: Option<u32>,
+    /// Maximum lifetime of a privileged session in seconds.
+    pub privilege_session_expiry: Option<u32>,
+    pub password_minimum_length: Option<u32>,
+    /// One of any, mfa, passkey or attested_passkey.
+    pub credential_type_minimum: Option<String>,
+    /// A file containing the webauthn attestation CA list as exported by fido-mds-tool.
+    pub webauthn_attestation_ca_list_file: Option<String>,
+    pub allow_primary_cred_fallback: Option<bool>,
+    pub limit_search_max_results: Option<u32>,
+    pub limit_search_max_filter_test: Option<u32>,
+}
+
+impl AccountPolicy {
+    /// Returns the kanidm attributes for all given settings.
+    pub fn attrs(&self) -> Result<Vec<(&'static str, Vec<String>)>> {
+        let mut attrs = Vec::new();
+        let mut push = |attr, value: Option<String>| {
+            if let Some(value) = value {
+                attrs.push((attr, vec![value]));
+            }
+        };
+
+        push("authsession_expiry", self.auth_session_expiry.map(|x| x.to_string()));
+        push("privilege_expiry", self.privilege_session_expiry.map(|x| x.to_string()));
+        push(
+            "auth_password_minimum_length",
+            self.password_minimum_length.map(|x| x.to_string()),
+        );
+        push("credential_type_minimum", self.credential_type_minimum.clone());
+        push(
+            "allow_primary_cred_fallback",
+            self.allow_primary_cred_fallback.map(|x| x.to_string()),
+        );
+        push(
+            "limit_search_max_results",
+            self.limit_search_max_results.map(|x| x.to_string()),
+        );
+        push(
+            "limit_search_max_filter_test",
+            self.limit_search_max_filter_test.map(|x| x.to_string()),
+        );
+        if let Some(file) = &self.webauthn_attestation_ca_list_file {
+            let ca_list = std::fs::read_to_string(file).wrap_err_with(|| format!("failed to read {:?}", file))?;
+            push("webauthn_attestation_ca_list", Some(ca_list.trim().to_string()));
+        }
+
+        Ok(attrs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let expiries = [
+            ("authSessionExpiry", self.auth_session_expiry),
+            ("privilegeSessionExpiry", self.privilege_session_expiry),
+        ];
+        for (field, expiry) in expiries {
+            if expiry == Some(0) {
+                bail!("{field} must be greater than 0");
+            }
+        }
+
+        if self.password_minimum_length.is_some_and(|x| x < MIN_PASSWORD_LENGTH) {
+            bail!("passwordMinimumLength must be at least {MIN_PASSWORD_LENGTH}");
+        }
+
+        if let Some(credential_type) = &self.credential_type_minimum {
+            if !["any", "mfa", "passkey", "attested_passkey"].contains(&credential_type.as_str()) {
+                bail!("credentialTypeMinimum must be one of any, mfa, passkey or attested_passkey");
+            }
+        }
+
+        if self.limit_search_max_results == Some(0) || self.limit_search_max_filter_test == Some(0) {
+            bail!("search limits must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+/// Shell commands that are run after an entity was changed. They receive the kind and name of the
+/// entity in KANIDM_PROVISION_ENTITY_KIND and KANIDM_PROVISION_ENTITY_NAME.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    /// Runs after the entity was created, including recreations.
+    pub post_create: Option<String>,
+    /// Runs after an existing entity was changed.
+    pub post_update: Option<String>,
+    /// Abort provisioning if a hook fails, instead of only reporting it as a warning.
+    #[serde(default = "default_false")]
+    pub abort_on_failure: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupUnix {
+    pub gid_number: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonUnix {
+    pub gid_number: Option<u32>,
+    /// kanidm never reveals the current unix password, so this is only applied when the
+    /// person becomes a posix account, or on every run if forceUnixPasswordReset is set.
+    pub unix_password_file: Option<String>,
+    #[serde(default = "default_false")]
+    pub force_unix_password_reset: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Person {
     #[serde(default = "default_true")]
     pub present: bool,
+    pub present_if: Option<String>,
+    #[serde(default = "default_false")]
+    pub adopt: bool,
+    #[serde(default)]
     pub display_name: String,
+    pub display_name_template: Option<String>,
     pub legal_name: Option<String>,
+    #[serde(default = "default_false")]
+    pub clear_legal_name: bool,
     pub mail_addresses: Option<Vec<String>>,
+    #[serde(default = "default_false")]
+    pub clear_mail_addresses: bool,
+    #[serde(default)]
+    pub member_of: Vec<String>,
+    pub radius_secret_file: Option<String>,
+    pub generate_password_to: Option<String>,
+    /// A duration like `90d`, after which the account expires. Only applied while the person has no expiry.
+    pub account_expire_in: Option<String>,
+    pub unix: Option<PersonUnix>,
+    pub hooks: Option<Hooks>,
+    #[serde(default)]
+    pub extra_attrs: HashMap<String, Vec<String>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ClaimMap {
     pub join_type: String,
+    #[serde(default)]
+    pub value_type: ClaimValueType,
+    #[serde(default)]
     pub values_by_group: HashMap<String, Vec<String>>,
+    /// Values for all persons, which are merged into valuesByGroup as the values of DEFAULT_CLAIM_GROUP.
+    #[serde(default)]
+    pub default_values: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// The group that receives the default values of claim maps. kanidm has no fallback for
+/// claim maps, but every person is a member of this group.
+pub const DEFAULT_CLAIM_GROUP: &str = "idm_all_persons";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub enum StringOrStrings {
     String(String),
@@ -45,47 +274,399 @@ impl StringOrStrings {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// The type of the values of a claim map. kanidm always emits claim values as strings,
+/// so the type only determines which values are accepted.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ClaimValueType {
+    /// Any string.
+    #[default]
+    String,
+    /// Exactly `true` or `false`.
+    Boolean,
+    /// A decimal integer without leading zeros or a plus sign.
+    Integer,
+}
+
+impl ClaimValueType {
+    fn as_str(self) -> &'static str {
+        match self {
+            ClaimValueType::String => "string",
+            ClaimValueType::Boolean => "boolean",
+            ClaimValueType::Integer => "integer",
+        }
+    }
+
+    /// Returns whether the given value is formatted canonically for this type, so that relying
+    /// parties that parse the string into the type see the intended value.
+    pub fn accepts(self, value: &str) -> bool {
+        match self {
+            ClaimValueType::String => true,
+            ClaimValueType::Boolean => matches!(value, "true" | "false"),
+            ClaimValueType::Integer => value.parse::<i64>().is_ok_and(|x| x.to_string() == value),
+        }
+    }
+}
+
+/// Whether PKCE is required for an oauth2 resource server.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Pkce {
+    /// Use kanidm's default, or `allowInsecureClientDisablePkce` if given.
+    #[default]
+    Default,
+    /// Always require PKCE.
+    Require,
+    /// Disable PKCE. Only possible for basic resource servers.
+    Disable,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Oauth2System {
     #[serde(default = "default_true")]
     pub present: bool,
+    pub present_if: Option<String>,
+    #[serde(default = "default_false")]
+    pub adopt: bool,
+    /// Disabled resource servers are kept, but without any origins no flow can complete.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
     #[serde(default = "default_false")]
     pub public: bool,
     pub display_name: String,
+    pub description: Option<String>,
+    #[serde(default = "default_false")]
+    pub clear_description: bool,
     pub basic_secret_file: Option<String>,
+    pub basic_secret_key: Option<String>,
+    pub image_file: Option<String>,
+    pub image_base64: Option<String>,
+    pub image_format: Option<String>,
     pub origin_url: StringOrStrings,
-    pub origin_landing: String,
+    pub origin_landing: Option<StringOrStrings>,
     #[serde(default = "default_false")]
     pub enable_localhost_redirects: bool,
     #[serde(default = "default_false")]
     pub enable_legacy_crypto: bool,
     #[serde(default = "default_false")]
     pub allow_insecure_client_disable_pkce: bool,
+    #[serde(default)]
+    pub pkce: Pkce,
     #[serde(default = "default_false")]
     pub prefer_short_username: bool,
+    #[serde(default = "default_false")]
+    pub enable_device_flow: bool,
+    pub strict_redirect_uri: Option<bool>,
+    pub access_token_lifetime: Option<u64>,
+    pub refresh_token_lifetime: Option<u64>,
     #[serde(default)]
     pub scope_maps: HashMap<String, Vec<String>>,
     #[serde(default)]
+    pub scope_maps_by_scope: HashMap<String, Vec<String>>,
+    #[serde(default)]
     pub supplementary_scope_maps: HashMap<String, Vec<String>>,
     #[serde(default = "default_true")]
+    pub remove_orphaned_scope_maps: bool,
+    #[serde(default)]
+    pub preserve_unmanaged_scope_maps: Vec<String>,
+    #[serde(default = "default_true")]
     pub remove_orphaned_claim_maps: bool,
     #[serde(default)]
     pub claim_maps: HashMap<String, ClaimMap>,
+    pub hooks: Option<Hooks>,
+    #[serde(default)]
+    pub extra_attrs: HashMap<String, Vec<String>>,
+}
+
+/// Returns the mime type for a supported image format, given as a file extension.
+fn image_mime_type(format: &str) -> Option<&'static str> {
+    match format.to_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "svg" => Some("image/svg+xml"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+impl Oauth2System {
+    /// Returns all given landing urls, which may be given as a single string or a list.
+    pub fn origin_landings(&self) -> Vec<String> {
+        self.origin_landing
+            .clone()
+            .map_or_else(Vec::new, StringOrStrings::strings)
+    }
+
+    /// Returns the desired basic secret, either read from basicSecretFile or looked up in the given secrets.
+    pub fn basic_secret(&self, secrets: &HashMap<String, String>) -> Result<Option<String>> {
+        if let Some(secret_file) = &self.basic_secret_file {
+            let secret =
+                std::fs::read_to_string(secret_file).wrap_err_with(|| format!("failed to read {:?}", secret_file))?;
+            return Ok(Some(secret));
+        }
+
+        if let Some(secret_key) = &self.basic_secret_key {
+            let Some(secret) = secrets.get(secret_key) else {
+                bail!("basicSecretKey '{secret_key}' was not found in the secrets file");
+            };
+            return Ok(Some(secret.clone()));
+        }
+
+        Ok(None)
+    }
+
+    /// Whether PKCE should be disabled, considering both `pkce` and the legacy `allowInsecureClientDisablePkce`.
+    pub fn disable_pkce(&self) -> bool {
+        match self.pkce {
+            Pkce::Default => self.allow_insecure_client_disable_pkce,
+            Pkce::Require => false,
+            Pkce::Disable => true,
+        }
+    }
+
+    /// Returns the image data and its mime type, if an image was configured.
+    pub fn image(&self) -> Result<Option<(Vec<u8>, &'static str)>> {
+        if let Some(image_file) = &self.image_file {
+            let format = Path::new(image_file)
+                .extension()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default();
+            let Some(mime_type) = image_mime_type(format) else {
+                bail!("Unsupported image format of {image_file}, must be one of png, jpg, gif, svg, webp");
+            };
+
+            let data = std::fs::read(image_file).wrap_err_with(|| format!("failed to read {:?}", image_file))?;
+            return Ok(Some((data, mime_type)));
+        }
+
+        if let Some(image_base64) = &self.image_base64 {
+            let format = self.image_format.as_deref().unwrap_or_default();
+            let Some(mime_type) = image_mime_type(format) else {
+                bail!("Unsupported imageFormat '{format}', must be one of png, jpg, gif, svg, webp");
+            };
+
+            let data = BASE64_STANDARD
+                .decode(image_base64.trim())
+                .wrap_err("imageBase64 is not valid base64")?;
+            return Ok(Some((data, mime_type)));
+        }
+
+        Ok(None)
+    }
+}
+
+/// An account used by an external identity source, e.g. an LDAP importer, to sync into kanidm.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncAccount {
+    #[serde(default = "default_true")]
+    pub present: bool,
+    pub present_if: Option<String>,
+    pub description: Option<String>,
+    pub token_file: Option<String>,
+    pub hooks: Option<Hooks>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Systems {
     pub oauth2: HashMap<String, Oauth2System>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct State {
     pub groups: HashMap<String, Group>,
     pub persons: HashMap<String, Person>,
     pub systems: Systems,
+    pub anonymous: Option<Anonymous>,
+    #[serde(default)]
+    pub sync_accounts: HashMap<String, SyncAccount>,
+    #[serde(default)]
+    pub keep_orphans: Vec<String>,
+}
+
+/// Settings of the built-in anonymous account.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Anonymous {
+    pub enabled: bool,
+}
+
+/// Entities that should be removed from kanidm, regardless of whether they were provisioned by this tool.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveState {
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub persons: Vec<String>,
+    #[serde(default)]
+    pub oauth2: Vec<String>,
+}
+
+impl RemoveState {
+    pub fn new(filename: impl AsRef<Path>) -> Result<RemoveState> {
+        parse_state_file(filename)
+    }
+}
+
+/// The CSV headers of the person fields that can be imported. By default, each header
+/// is the name of the field in the state file.
+#[derive(Debug, Clone)]
+pub struct PersonCsvColumns {
+    pub name: String,
+    pub display_name: String,
+    /// Multiple addresses in this column are separated by ';'.
+    pub mail_addresses: String,
+}
+
+impl Default for PersonCsvColumns {
+    fn default() -> Self {
+        PersonCsvColumns {
+            name: "name".to_string(),
+            display_name: "displayName".to_string(),
+            mail_addresses: "mailAddresses".to_string(),
+        }
+    }
+}
+
+impl PersonCsvColumns {
+    /// Reads the given field from the header with the given name instead.
+    pub fn set(&mut self, field: &str, header: &str) -> Result<()> {
+        let column = match field {
+            "name" => &mut self.name,
+            "displayName" => &mut self.display_name,
+            "mailAddresses" => &mut self.mail_addresses,
+            _ => bail!("Unknown person field '{field}', must be one of name, displayName or mailAddresses"),
+        };
+        *column = header.to_string();
+        Ok(())
+    }
+}
+
+/// Parses a duration like `90d` or `1w12h`, made up of numbers followed by one of the
+/// units s, m, h, d or w.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let mut secs: u64 = 0;
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        bail!("Duration must not be empty");
+    }
+
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| eyre!("Expected a number in duration '{duration}'"))?;
+        let mut chars = rest[digits..].chars();
+        let unit = match chars.next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            Some('w') => 7 * 24 * 60 * 60,
+            _ => bail!("Expected one of the units s, m, h, d or w after {value} in duration '{duration}'"),
+        };
+        secs = value
+            .checked_mul(unit)
+            .and_then(|x| secs.checked_add(x))
+            .ok_or_else(|| eyre!("Duration '{duration}' is too large"))?;
+        rest = chars.as_str();
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Splits CSV content into records of fields. Fields may be quoted with '"', in which case they
+/// can contain commas, line breaks and quotes, which are escaped by doubling them.
+fn parse_csv(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        bail!("Unterminated quoted field");
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Empty lines don't contain any record
+    records.retain(|x| x.len() > 1 || x.first().is_some_and(|x| !x.is_empty()));
+    Ok(records)
+}
+
+/// Evaluates a condition of the form `VARIABLE == value` or `VARIABLE != value` against
+/// the environment. Unset variables are treated as empty.
+fn evaluate_condition(condition: &str) -> Result<bool> {
+    let (variable, value, equal) = if let Some((variable, value)) = condition.split_once("==") {
+        (variable, value, true)
+    } else if let Some((variable, value)) = condition.split_once("!=") {
+        (variable, value, false)
+    } else {
+        bail!("'{condition}' must be of the form 'VARIABLE == value' or 'VARIABLE != value'");
+    };
+
+    let variable = variable.trim();
+    if variable.is_empty() {
+        bail!("'{condition}' does not name an environment variable");
+    }
+
+    let value = value.trim().trim_matches('"');
+    let current = std::env::var(variable).unwrap_or_default();
+    Ok((current == value) == equal)
+}
+
+/// Reads a secrets file, which is a JSON object mapping keys to secret values.
+pub fn read_secrets_file(filename: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+    let file_content = std::fs::read_to_string(filename.as_ref())
+        .context(format!("Failed to read secrets file: {}", filename.as_ref().display()))?;
+    serde_json::from_str(&file_content).context("Failed to parse secrets file, must be a JSON object of strings")
+}
+
+/// Reads and parses a state file. Files ending in .toml are parsed as TOML, everything else as JSON.
+fn parse_state_file<T: DeserializeOwned>(filename: impl AsRef<Path>) -> Result<T> {
+    let file_content = std::fs::read_to_string(filename.as_ref())
+        .context(format!("Failed to read state file: {}", filename.as_ref().display()))?;
+    let is_toml = filename.as_ref().extension().is_some_and(|x| x == "toml");
+    parse_state(&file_content, is_toml)
+}
+
+fn parse_state<T: DeserializeOwned>(content: &str, is_toml: bool) -> Result<T> {
+    if is_toml {
+        toml::from_str(content).context("Failed to parse state")
+    } else {
+        serde_json::from_str(content).context("Failed to parse state")
+    }
+}
+
+/// Whether the group ships with kanidm, like `idm_all_persons`. Such groups can be
+/// referenced without being declared in the state.
+fn is_builtin_group(name: &str) -> bool {
+    name.starts_with("idm_") || ["system_admins", "domain_admins"].contains(&name)
 }
 
 fn default_false() -> bool {
@@ -97,9 +678,617 @@ fn default_true() -> bool {
 
 impl State {
     pub fn new(filename: impl AsRef<Path>) -> Result<State> {
-        let file_content = std::fs::read_to_string(filename.as_ref())
-            .context(format!("Failed to read state file: {}", filename.as_ref().display()))?;
-        let state: State = serde_json::from_str(&file_content).context("Failed to parse state")?;
+        State::finish(parse_state_file(filename)?)
+    }
+
+    /// Parses the given state, which is either JSON or TOML.
+    pub fn from_str(content: &str, is_toml: bool) -> Result<State> {
+        State::finish(parse_state(content, is_toml)?)
+    }
+
+    /// Fetches the state over HTTP(S). It is parsed as TOML if the url path
+    /// ends in .toml or the server reports a TOML content type, and as JSON otherwise.
+    pub fn from_url(url: &str, accept_invalid_certs: bool) -> Result<State> {
+        let response = Client::builder()
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .build()?
+            .get(url)
+            .send()
+            .and_then(|x| x.error_for_status())
+            .wrap_err_with(|| format!("Failed to fetch state from {url}"))?;
+
+        let is_toml = response.url().path().ends_with(".toml")
+            || response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .is_some_and(|x| x.contains("toml"));
+        let content = response
+            .text()
+            .wrap_err_with(|| format!("Failed to fetch state from {url}"))?;
+        State::from_str(&content, is_toml)
+    }
+
+    /// Adds all persons from a CSV file with a header row, e.g. to migrate from another system.
+    /// The imported persons are validated and tracked exactly like declared ones. Returns the
+    /// number of imported persons.
+    pub fn import_persons_csv(&mut self, filename: impl AsRef<Path>, columns: &PersonCsvColumns) -> Result<usize> {
+        let filename = filename.as_ref();
+        let content = std::fs::read_to_string(filename)
+            .wrap_err_with(|| format!("Failed to read person CSV: {}", filename.display()))?;
+        let records =
+            parse_csv(&content).wrap_err_with(|| format!("Failed to parse person CSV: {}", filename.display()))?;
+        let Some((header, records)) = records.split_first() else {
+            return Ok(0);
+        };
+
+        let position = |column: &str| header.iter().position(|x| x.trim() == column);
+        let Some(name_index) = position(&columns.name) else {
+            bail!("Person CSV {} has no '{}' column", filename.display(), columns.name);
+        };
+        let Some(display_name_index) = position(&columns.display_name) else {
+            bail!(
+                "Person CSV {} has no '{}' column",
+                filename.display(),
+                columns.display_name
+            );
+        };
+        let mail_index = position(&columns.mail_addresses);
+
+        for (i, record) in records.iter().enumerate() {
+            // The header is line 1
+            let line = i + 2;
+            let field = |index: usize| record.get(index).map_or("", |x| x.trim());
+            let name = field(name_index);
+            let display_name = field(display_name_index);
+            if name.is_empty() || display_name.is_empty() {
+                bail!(
+                    "Person in line {line} of {} has no name or display name",
+                    filename.display()
+                );
+            }
+            if self.persons.contains_key(name) {
+                bail!(
+                    "Person '{name}' in line {line} of {} is already declared",
+                    filename.display()
+                );
+            }
+
+            let mail_addresses: Vec<&str> = mail_index
+                .map(field)
+                .into_iter()
+                .flat_map(|x| x.split(';'))
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .collect();
+            let mut person = json!({ "displayName": display_name });
+            if !mail_addresses.is_empty() {
+                person["mailAddresses"] = json!(mail_addresses);
+            }
+            let person = serde_json::from_value(person)?;
+            self.persons.insert(name.to_string(), person);
+        }
+
+        self.validate()
+            .wrap_err_with(|| format!("Invalid person imported from {}", filename.display()))?;
+        Ok(records.len())
+    }
+
+    /// Applies all post-processing and validation to a freshly parsed state.
+    fn finish(mut state: State) -> Result<State> {
+        state.resolve_present_if()?;
+        state.expand_templates()?;
+        state.read_members_files()?;
+        state.merge_member_of()?;
+        state.merge_scope_maps_by_scope()?;
+        state.merge_claim_map_defaults()?;
+        state.validate()?;
         Ok(state)
     }
+
+    /// Feeds the normalized state and the contents of all files it reads during provisioning
+    /// into the given hasher. Files that are written by this tool, like generatePasswordTo, are excluded.
+    pub fn fingerprint(&self, hasher: &mut impl Hasher) -> Result<()> {
+        // Maps are sorted when converted to a json value, so the order in the state file doesn't matter
+        serde_json::to_value(self)?.to_string().hash(hasher);
+
+        let files = self
+            .persons
+            .values()
+            .flat_map(|x| {
+                let unix_password_file = x.unix.as_ref().and_then(|x| x.unix_password_file.as_ref());
+                [x.radius_secret_file.as_ref(), unix_password_file]
+            })
+            .chain(
+                self.systems
+                    .oauth2
+                    .values()
+                    .flat_map(|x| [x.basic_secret_file.as_ref(), x.image_file.as_ref()]),
+            )
+            .chain(
+                self.groups
+                    .values()
+                    .filter_map(|x| x.account_policy.as_ref())
+                    .map(|x| x.webauthn_attestation_ca_list_file.as_ref()),
+            )
+            .flatten();
+        let mut files: Vec<&String> = files.collect();
+        files.sort();
+        for file in files {
+            file.hash(hasher);
+            // A missing file fails provisioning, so such a run is never recorded anyway
+            std::fs::read(file).ok().hash(hasher);
+        }
+
+        Ok(())
+    }
+
+    /// Whether any entity declares hooks.
+    pub fn has_hooks(&self) -> bool {
+        self.groups.values().any(|x| x.hooks.is_some())
+            || self.persons.values().any(|x| x.hooks.is_some())
+            || self.systems.oauth2.values().any(|x| x.hooks.is_some())
+            || self.sync_accounts.values().any(|x| x.hooks.is_some())
+    }
+
+    /// Returns the names of all entities that are managed but must never be tracked for orphan removal.
+    pub fn adopted_entities(&self) -> HashSet<String> {
+        let groups = self.groups.iter().filter(|(_, x)| x.adopt).map(|(name, _)| name);
+        let persons = self
+            .persons
+            .iter()
+            .filter(|(name, x)| x.adopt || BUILTIN_ACCOUNTS.contains(&name.as_str()))
+            .map(|(name, _)| name);
+        let oauth2s = self
+            .systems
+            .oauth2
+            .iter()
+            .filter(|(_, x)| x.adopt)
+            .map(|(name, _)| name);
+        groups.chain(persons).chain(oauth2s).cloned().collect()
+    }
+
+    /// Returns the names of all entities that must never be removed as orphans. Kept orphans
+    /// stay in the tracking group, so they are removed once they are no longer kept.
+    pub fn kept_entities(&self) -> HashSet<String> {
+        let mut kept_entities = self.adopted_entities();
+        kept_entities.extend(self.keep_orphans.iter().cloned());
+        kept_entities
+    }
+
+    fn read_members_files(&mut self) -> Result<()> {
+        for (name, group) in &mut self.groups {
+            let Some(members_file) = &group.members_file else {
+                continue;
+            };
+
+            let content = std::fs::read_to_string(members_file)
+                .wrap_err_with(|| format!("Failed to read members file of group '{name}': {members_file}"))?;
+
+            let members = group.members.get_or_insert_with(Vec::new);
+            for line in content.lines() {
+                let member = line.split_once('#').map(|x| x.0).unwrap_or(line).trim();
+                if !member.is_empty() && !members.iter().any(|x| x == member) {
+                    members.push(member.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds every present person to the members of the groups listed in its `memberOf`.
+    fn merge_member_of(&mut self) -> Result<()> {
+        // Merged in a fixed order, so that the resulting state is the same on every run
+        let mut persons: Vec<_> = self.persons.iter().filter(|(_, x)| x.present).collect();
+        persons.sort_by_key(|(name, _)| *name);
+        for (name, person) in persons {
+            for group_name in &person.member_of {
+                let Some(group) = self.groups.get_mut(group_name).filter(|x| x.present) else {
+                    bail!("Person '{name}' is a member of group '{group_name}', which is not declared as present in the state");
+                };
+
+                let members = group.members.get_or_insert_with(Vec::new);
+                if !members.contains(name) {
+                    members.push(name.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges the scope-first `scopeMapsByScope` into the group-first `scopeMaps`.
+    fn merge_scope_maps_by_scope(&mut self) -> Result<()> {
+        for (name, oauth2) in &mut self.systems.oauth2 {
+            // An empty list in scopeMaps removes the scope map of that group,
+            // which contradicts granting it a scope by scope.
+            let removed_groups: HashSet<String> = oauth2
+                .scope_maps
+                .iter()
+                .filter(|(_, scopes)| scopes.is_empty())
+                .map(|(group, _)| group.clone())
+                .collect();
+
+            // Merged in a fixed order, so that the resulting state is the same on every run
+            let mut scope_maps_by_scope: Vec<_> = std::mem::take(&mut oauth2.scope_maps_by_scope).into_iter().collect();
+            scope_maps_by_scope.sort();
+            for (scope, groups) in scope_maps_by_scope {
+                for group in groups {
+                    if removed_groups.contains(&group) {
+                        bail!("oauth2 resource server '{name}' grants scope '{scope}' to group '{group}' in scopeMapsByScope, but removes its scope map in scopeMaps");
+                    }
+
+                    let scopes = oauth2.scope_maps.entry(group).or_default();
+                    if !scopes.contains(&scope) {
+                        scopes.push(scope.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges the defaultValues of all claim maps into their valuesByGroup, so that they are synced
+    /// and never removed as orphans like any other group of the claim.
+    fn merge_claim_map_defaults(&mut self) -> Result<()> {
+        for (name, oauth2) in &mut self.systems.oauth2 {
+            for (claim, claim_map) in &mut oauth2.claim_maps {
+                if claim_map.default_values.is_empty() {
+                    continue;
+                }
+
+                if claim_map.values_by_group.contains_key(DEFAULT_CLAIM_GROUP) {
+                    bail!("Claim '{claim}' of oauth2 resource server '{name}' must not set both defaultValues and valuesByGroup.{DEFAULT_CLAIM_GROUP}");
+                }
+
+                let default_values = std::mem::take(&mut claim_map.default_values);
+                claim_map
+                    .values_by_group
+                    .insert(DEFAULT_CLAIM_GROUP.to_string(), default_values);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Treats every entity whose presentIf condition doesn't hold as `present: false`.
+    fn resolve_present_if(&mut self) -> Result<()> {
+        let groups = self
+            .groups
+            .iter_mut()
+            .map(|(name, x)| (name, &x.present_if, &mut x.present));
+        let persons = self
+            .persons
+            .iter_mut()
+            .map(|(name, x)| (name, &x.present_if, &mut x.present));
+        let oauth2s = self
+            .systems
+            .oauth2
+            .iter_mut()
+            .map(|(name, x)| (name, &x.present_if, &mut x.present));
+        let sync_accounts = self
+            .sync_accounts
+            .iter_mut()
+            .map(|(name, x)| (name, &x.present_if, &mut x.present));
+        for (name, present_if, present) in groups.chain(persons).chain(oauth2s).chain(sync_accounts) {
+            if let Some(condition) = present_if {
+                let holds = evaluate_condition(condition)
+                    .wrap_err_with(|| format!("Invalid presentIf condition of entity '{name}'"))?;
+                *present = *present && holds;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expand_templates(&mut self) -> Result<()> {
+        for (name, person) in &mut self.persons {
+            if !person.display_name.is_empty() {
+                continue;
+            }
+
+            let Some(template) = &person.display_name_template else {
+                bail!("Person '{name}' requires either displayName or displayNameTemplate");
+            };
+
+            let mut display_name = template.replace("{name}", name);
+            if display_name.contains("{legalName}") {
+                let Some(legal_name) = &person.legal_name else {
+                    bail!("displayNameTemplate of person '{name}' references {{legalName}}, but legalName is not set");
+                };
+                display_name = display_name.replace("{legalName}", legal_name);
+            }
+
+            person.display_name = display_name;
+        }
+
+        Ok(())
+    }
+
+    /// Whether any oauth2 origin uses the {domain} placeholder.
+    pub fn uses_domain_placeholder(&self) -> bool {
+        self.systems.oauth2.values().any(|oauth2| {
+            let mut urls = oauth2.origin_url.clone().strings();
+            urls.extend(oauth2.origin_landings());
+            urls.iter().any(|x| x.contains("{domain}"))
+        })
+    }
+
+    /// Replaces the {domain} placeholder in all oauth2 origins.
+    pub fn expand_domain(&mut self, domain: &str) {
+        for oauth2 in self.systems.oauth2.values_mut() {
+            let origin_urls = oauth2.origin_url.clone().strings();
+            oauth2.origin_url =
+                StringOrStrings::Strings(origin_urls.iter().map(|x| x.replace("{domain}", domain)).collect());
+            if oauth2.origin_landing.is_some() {
+                let origin_landings = oauth2.origin_landings();
+                oauth2.origin_landing = Some(StringOrStrings::Strings(
+                    origin_landings.iter().map(|x| x.replace("{domain}", domain)).collect(),
+                ));
+            }
+        }
+    }
+
+    /// Resolves relative paths of secret files against `secrets_dir` and relative image files
+    /// against `assets_dir`. Absolute paths are kept unchanged.
+    pub fn resolve_relative_paths(&mut self, secrets_dir: Option<&Path>, assets_dir: Option<&Path>) {
+        fn resolve(dir: Option<&Path>, file: &mut Option<String>) {
+            if let (Some(dir), Some(file)) = (dir, file) {
+                if Path::new(file).is_relative() {
+                    *file = dir.join(&file).to_string_lossy().into_owned();
+                }
+            }
+        }
+
+        for person in self.persons.values_mut() {
+            resolve(secrets_dir, &mut person.radius_secret_file);
+            resolve(secrets_dir, &mut person.generate_password_to);
+            if let Some(unix) = &mut person.unix {
+                resolve(secrets_dir, &mut unix.unix_password_file);
+            }
+        }
+        for oauth2 in self.systems.oauth2.values_mut() {
+            resolve(secrets_dir, &mut oauth2.basic_secret_file);
+            resolve(assets_dir, &mut oauth2.image_file);
+        }
+        for sync_account in self.sync_accounts.values_mut() {
+            resolve(secrets_dir, &mut sync_account.token_file);
+        }
+    }
+
+    /// Searches the declared group memberships for a cycle and returns the
+    /// groups that form it, starting and ending with the same group.
+    fn find_group_cycle(&self) -> Option<Vec<String>> {
+        fn visit<'a>(
+            state: &'a State,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+        ) -> Option<Vec<String>> {
+            if let Some(pos) = path.iter().position(|x| *x == name) {
+                let mut cycle: Vec<String> = path[pos..].iter().map(|x| x.to_string()).collect();
+                cycle.push(name.to_string());
+                return Some(cycle);
+            }
+
+            if done.contains(name) {
+                return None;
+            }
+
+            path.push(name);
+            let mut members: Vec<&str> = state.groups[name]
+                .members
+                .iter()
+                .flatten()
+                .filter(|x| state.groups.contains_key(*x))
+                .map(|x| x.as_str())
+                .collect();
+            members.sort_unstable();
+            for member in members {
+                if let Some(cycle) = visit(state, member, path, done) {
+                    return Some(cycle);
+                }
+            }
+            path.pop();
+            done.insert(name);
+
+            None
+        }
+
+        let mut names: Vec<&String> = self.groups.keys().collect();
+        names.sort_unstable();
+
+        let mut done = HashSet::new();
+        names
+            .into_iter()
+            .find_map(|name| visit(self, name, &mut Vec::new(), &mut done))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let names = self
+            .groups
+            .keys()
+            .chain(self.persons.keys())
+            .chain(self.systems.oauth2.keys())
+            .chain(self.sync_accounts.keys());
+        for name in names {
+            if *name != name.to_lowercase() {
+                bail!("Entity name '{name}' must be lowercase, kanidm does not preserve the case of names");
+            }
+            if name.contains(|x: char| x == '@' || x.is_whitespace()) {
+                bail!("Entity name '{name}' must not contain '@' or whitespace, kanidm derives the spn as name@domain");
+            }
+        }
+
+        for (name, group) in &self.groups {
+            for attr in group.extra_attrs.keys() {
+                if GROUP_MANAGED_ATTRS.contains(&attr.as_str()) {
+                    bail!("extraAttrs of group '{name}' must not contain '{attr}', which is managed by this tool");
+                }
+            }
+
+            // Checked after merging, so members from membersFile or memberOf are also caught
+            if group.member_filter.is_some() && group.members.as_ref().is_some_and(|x| !x.is_empty()) {
+                bail!("Group '{name}' must not have static members when memberFilter is set");
+            }
+
+            if let Some(account_policy) = &group.account_policy {
+                account_policy
+                    .validate()
+                    .wrap_err_with(|| format!("Invalid account policy of group '{name}'"))?;
+                if let Some(attr) = group
+                    .extra_attrs
+                    .keys()
+                    .find(|x| ACCOUNT_POLICY_ATTRS.contains(&x.as_str()))
+                {
+                    bail!("extraAttrs of group '{name}' must not contain '{attr}', which is managed by accountPolicy");
+                }
+            }
+        }
+
+        for (name, person) in &self.persons {
+            if BUILTIN_ACCOUNTS.contains(&name.as_str()) {
+                let only_supported = person.present
+                    && person.legal_name.is_none()
+                    && person.radius_secret_file.is_none()
+                    && person.generate_password_to.is_none()
+                    && person.account_expire_in.is_none()
+                    && person.unix.is_none()
+                    && person.hooks.is_none()
+                    && person.extra_attrs.is_empty();
+                if !only_supported {
+                    bail!("Built-in account '{name}' must be present and only supports displayName and mailAddresses");
+                }
+            }
+
+            if person.clear_legal_name && person.legal_name.is_some() {
+                bail!("Person '{name}' must not set both legalName and clearLegalName");
+            }
+
+            if person.clear_mail_addresses && person.mail_addresses.is_some() {
+                bail!("Person '{name}' must not set both mailAddresses and clearMailAddresses");
+            }
+
+            if let Some(expire_in) = &person.account_expire_in {
+                parse_duration(expire_in).wrap_err_with(|| format!("Invalid accountExpireIn of person '{name}'"))?;
+                if person.extra_attrs.contains_key("account_expire") {
+                    bail!("Person '{name}' must not set both accountExpireIn and extraAttrs.account_expire");
+                }
+            }
+
+            for attr in person.extra_attrs.keys() {
+                if attr == "spn" {
+                    bail!("extraAttrs of person '{name}' must not contain 'spn', which kanidm always derives from the name");
+                }
+                if PERSON_MANAGED_ATTRS.contains(&attr.as_str()) {
+                    bail!("extraAttrs of person '{name}' must not contain '{attr}', which is managed by this tool");
+                }
+            }
+        }
+
+        if let Some(cycle) = self.find_group_cycle() {
+            bail!("Group memberships must not contain cycles: {}", cycle.join(" -> "));
+        }
+
+        self.check_group_references()?;
+
+        for (name, oauth2) in &self.systems.oauth2 {
+            for attr in oauth2.extra_attrs.keys() {
+                if OAUTH2_MANAGED_ATTRS.contains(&attr.as_str()) {
+                    bail!("extraAttrs of oauth2 resource server '{name}' must not contain '{attr}', which is managed by this tool");
+                }
+            }
+
+            if oauth2.pkce == Pkce::Require && oauth2.allow_insecure_client_disable_pkce {
+                bail!("pkce is set to require, but allowInsecureClientDisablePkce is enabled for oauth2 resource server '{name}'");
+            }
+
+            if oauth2.basic_secret_file.is_some() && oauth2.basic_secret_key.is_some() {
+                bail!(
+                    "Only one of basicSecretFile and basicSecretKey may be given for oauth2 resource server '{name}'"
+                );
+            }
+
+            if oauth2.image_file.is_some() && oauth2.image_base64.is_some() {
+                bail!("Only one of imageFile and imageBase64 may be given for oauth2 resource server '{name}'");
+            }
+
+            // The join type applies to all groups of a claim, so it is validated once per claim
+            for (claim, claim_map) in &oauth2.claim_maps {
+                if !matches!(claim_map.join_type.as_str(), "ssv" | "csv" | "array") {
+                    bail!(
+                        "Invalid joinType '{}' of claim '{claim}' for oauth2 resource server '{name}', must be one of ssv, csv or array",
+                        claim_map.join_type
+                    );
+                }
+
+                let invalid_value = claim_map
+                    .values_by_group
+                    .values()
+                    .flatten()
+                    .find(|x| !claim_map.value_type.accepts(x));
+                if let Some(value) = invalid_value {
+                    bail!(
+                        "Value '{value}' of claim '{claim}' for oauth2 resource server '{name}' is not a valid {}",
+                        claim_map.value_type.as_str()
+                    );
+                }
+            }
+
+            if oauth2.image_base64.is_some() {
+                oauth2
+                    .image()
+                    .wrap_err_with(|| format!("Invalid image for oauth2 resource server '{name}'"))?;
+            }
+
+            // The landing url is a single value attribute in kanidm, the list form only exists for symmetry with originUrl
+            let origin_landings = oauth2.origin_landings();
+            if origin_landings.len() > 1 {
+                bail!(
+                    "oauth2 resource server '{name}' has {} originLanding urls, but kanidm only supports a single landing url per resource server",
+                    origin_landings.len()
+                );
+            }
+
+            let mut urls = oauth2.origin_url.clone().strings();
+            urls.extend(origin_landings);
+            for url in &urls {
+                // {domain} is the only supported placeholder and is replaced before provisioning
+                if url.replace("{domain}", "").contains(['{', '}']) {
+                    bail!("Origin '{url}' of oauth2 resource server '{name}' contains an unknown placeholder");
+                }
+            }
+
+            for origin_url in oauth2.origin_url.clone().strings() {
+                // The domain is only known later, so any valid hostname will do for this check
+                if let Err(e) = Url::parse(&origin_url.replace("{domain}", "example.com")) {
+                    bail!("Invalid originUrl '{origin_url}' for oauth2 resource server '{name}': {e}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensures that all groups referenced by scope maps and claim maps are declared in this state.
+    fn check_group_references(&self) -> Result<()> {
+        for (name, oauth2) in &self.systems.oauth2 {
+            let referenced_groups = oauth2
+                .scope_maps
+                .keys()
+                .chain(oauth2.supplementary_scope_maps.keys())
+                .chain(oauth2.claim_maps.values().flat_map(|x| x.values_by_group.keys()));
+            for group in referenced_groups.map(|x| strip_domain(x)) {
+                if !self.groups.contains_key(group) && !is_builtin_group(group) {
+                    bail!(
+                        "oauth2 resource server '{name}' refers to group '{group}', which is not declared in the state"
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
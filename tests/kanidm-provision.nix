@@ -85,6 +85,7 @@
               persons.testuser2 = {
                 displayName = "Powerful Test User";
                 legalName = "Ryouiki Tenkai";
+                accountExpireIn = "90d";
                 groups = [ "service1-admin" ];
               };
 
@@ -100,9 +101,20 @@
                   "email"
                   "profile"
                 ];
+                scopeMaps.service1-admin = [ "openid" ];
                 supplementaryScopeMaps.service1-admin = [ "admin" ];
+                supplementaryScopeMaps.idm_all_persons = [ "directory" ];
                 claimMaps.groups = {
                   valuesByGroup.service1-admin = [ "admin" ];
+                  valuesByGroup.service1-access = [ "access" ];
+                };
+                claimMaps.role = {
+                  defaultValues = [ "user" ];
+                  valuesByGroup.service1-admin = [ "admin" ];
+                };
+                claimMaps.is_admin = {
+                  valueType = "boolean";
+                  valuesByGroup.service1-admin = [ "true" ];
                 };
               };
 
@@ -137,7 +149,7 @@
                 legalName = "Jane Doe (changed)";
                 mailAddresses = [
                   "jane.doe@example.com"
-                  "second.doe@example.com"
+                  "Second.Doe@Example.com"
                 ];
                 groups = [
                   #"testgroup1"
@@ -148,6 +160,7 @@
               persons.testuser2 = {
                 displayName = "Powerful Test User (changed)";
                 legalName = "Ryouiki Tenkai (changed)";
+                accountExpireIn = "90d";
                 groups = [ "service1-admin" ];
               };
 
@@ -220,6 +233,14 @@
                 originUrl = "https://changed-two.example.com/";
                 originLanding = "https://changed-landing2.example.com/";
               };
+
+              systems.oauth2.service3 = {
+                enabled = false;
+                displayName = "Service Three";
+                originUrl = "https://three.example.com/";
+                originLanding = "https://three.example.com/";
+                basicSecretFile = pkgs.writeText "bs-service3" "very-strong-secret-for-service3";
+              };
             };
           };
 
@@ -257,9 +278,214 @@
         serverConfigFile = (pkgs.formats.toml { }).generate "server.toml" filteredConfig;
 
         specialisations = "${nodes.provision.system.build.toplevel}/specialisation";
+        provisionExe = pkgs.lib.getExe pkgs.kanidm-provision;
+        emptyState = pkgs.writeText "empty-state.json" (
+          builtins.toJSON {
+            groups = { };
+            persons = { };
+            systems.oauth2 = { };
+          }
+        );
+        posixState =
+          gidNumber:
+          pkgs.writeText "posix-state-${toString gidNumber}.json" (
+            builtins.toJSON {
+              groups = { };
+              persons.posixuser1 = {
+                displayName = "Posix User";
+                unix.gidNumber = gidNumber;
+              };
+              systems.oauth2 = { };
+            }
+          );
+        # Quoted fields may contain commas and quotes, which are escaped by doubling them
+        personsCsv = pkgs.writeText "persons.csv" ''
+          name,displayName,mailAddresses
+          csvuser1,"Doe, Jane","jane.doe@example.com;j.doe@example.com"
+          csvuser2,"The ""Boss""",
+        '';
+        scopeMapState = pkgs.writeText "scope-map-state.json" (
+          builtins.toJSON {
+            groups.mygroup = { };
+            persons = { };
+            systems.oauth2.scopeservice = {
+              displayName = "Scope Service";
+              originUrl = "https://scope.example.com/";
+              originLanding = "https://scope.example.com/";
+              scopeMaps.mygroup = [ "openid" ];
+            };
+          }
+        );
+        builtinAccountState = pkgs.writeText "builtin-account-state.json" (
+          builtins.toJSON {
+            groups = { };
+            persons.idm_admin = {
+              displayName = "Provisioned IDM Admin";
+              mailAddresses = [ "idm_admin@example.com" ];
+            };
+            systems.oauth2 = { };
+          }
+        );
+        mailState =
+          mailAddresses:
+          pkgs.writeText "mail-state.json" (
+            builtins.toJSON {
+              groups = { };
+              persons.mailuser1 = {
+                displayName = "Mail User";
+                inherit mailAddresses;
+              };
+              systems.oauth2 = { };
+            }
+          );
+        # Destroys all existing sessions of idm_admin, including the one used by the provisioning run.
+        # The CLI logs in again before every destroy, so it never revokes the session it is using.
+        revokeIdmAdminSessions = pkgs.writeShellScript "revoke-idm-admin-sessions" ''
+          set -euo pipefail
+          ids=$(kanidm service-account session status idm_admin -D idm_admin | grep -o 'session_id: .*' | cut -d' ' -f2)
+          for id in $ids; do
+            KANIDM_PASSWORD=${provisionIdmAdminPassword} kanidm login -D idm_admin >/dev/null
+            kanidm service-account session destroy idm_admin "$id" -D idm_admin
+          done
+        '';
+        reauthState = pkgs.writeText "reauth-state.json" (
+          builtins.toJSON {
+            groups.reauthgroup1.hooks = {
+              postCreate = "${revokeIdmAdminSessions}";
+              abortOnFailure = true;
+            };
+            groups.reauthgroup2 = { };
+            persons = { };
+            systems.oauth2 = { };
+          }
+        );
+        # Secret files usually end with a newline, which must not become part of the secret
+        secretFileState = pkgs.writeText "secret-file-state.json" (
+          builtins.toJSON {
+            groups = { };
+            persons = { };
+            systems.oauth2.lfservice = {
+              displayName = "LF Service";
+              originUrl = "https://lf.example.com/";
+              originLanding = "https://lf.example.com/";
+              basicSecretFile = pkgs.writeText "bs-lfservice" "lf-secret-for-lfservice\n";
+            };
+            systems.oauth2.crlfservice = {
+              displayName = "CRLF Service";
+              originUrl = "https://crlf.example.com/";
+              originLanding = "https://crlf.example.com/";
+              basicSecretFile = pkgs.writeText "bs-crlfservice" "crlf-secret-for-crlfservice\r\n";
+            };
+          }
+        );
+        # Members and scope map groups may be given with or without the domain
+        domainSuffixState = pkgs.writeText "domain-suffix-state.json" (
+          builtins.toJSON {
+            groups.domaingroup1 = { };
+            groups.domaingroup2.members = [ "domaingroup1@${serverDomain}" ];
+            groups.domaingroup3.members = [ "domaingroup1" ];
+            persons = { };
+            systems.oauth2.domainservice = {
+              displayName = "Domain Service";
+              originUrl = "https://domain.example.com/";
+              originLanding = "https://domain.example.com/";
+              scopeMaps."domaingroup2@${serverDomain}" = [ "openid" ];
+              scopeMaps.domaingroup3 = [ "email" ];
+            };
+          }
+        );
+        pkceState =
+          {
+            pkce,
+            allowInsecureClientDisablePkce ? false,
+            public ? false,
+          }:
+          pkgs.writeText "pkce-state.json" (
+            builtins.toJSON {
+              groups = { };
+              persons = { };
+              systems.oauth2.pkceservice = {
+                displayName = "PKCE Service";
+                originUrl = "https://pkce.example.com/";
+                originLanding = "https://pkce.example.com/";
+                inherit pkce allowInsecureClientDisablePkce public;
+              };
+            }
+          );
+        # Neither the legal name and mail addresses nor the group members are given
+        mergeState = pkgs.writeText "merge-state.json" (
+          builtins.toJSON {
+            groups.mergegroup = { };
+            persons.mergeuser1.displayName = "Merge User";
+            persons.mergeuser2.displayName = "Other Merge User";
+            systems.oauth2 = { };
+          }
+        );
+        originState =
+          originUrl:
+          pkgs.writeText "origin-state.json" (
+            builtins.toJSON {
+              groups = { };
+              persons = { };
+              systems.oauth2.originservice = {
+                displayName = "Origin Service";
+                inherit originUrl;
+                originLanding = "https://one.origin.example.com/";
+              };
+            }
+          );
+        deviceFlowState =
+          enableDeviceFlow:
+          pkgs.writeText "device-flow-state.json" (
+            builtins.toJSON {
+              groups = { };
+              persons = { };
+              systems.oauth2.deviceflowservice = {
+                displayName = "Device Flow Service";
+                originUrl = "https://deviceflow.example.com/";
+                originLanding = "https://deviceflow.example.com/";
+                public = true;
+                inherit enableDeviceFlow;
+              };
+            }
+          );
+        duplicateNameState = pkgs.writeText "duplicate-name-state.json" (
+          builtins.toJSON {
+            groups.dupname = { };
+            persons.dupname.displayName = "Duplicate Name";
+            systems.oauth2 = { };
+          }
+        );
+        missingGroupState = pkgs.writeText "missing-group-state.json" (
+          builtins.toJSON {
+            groups = { };
+            persons = { };
+            systems.oauth2.missinggroupservice = {
+              displayName = "Missing Group Service";
+              originUrl = "https://missing.example.com/";
+              originLanding = "https://missing.example.com/";
+              scopeMaps.undeclaredgroup = [ "openid" ];
+            };
+          }
+        );
+        badUrlState = pkgs.writeText "bad-url-state.json" (
+          builtins.toJSON {
+            groups = { };
+            persons = { };
+            systems.oauth2.badurlservice = {
+              displayName = "Bad Url Service";
+              originUrl = "not a url";
+              originLanding = "https://badurl.example.com/";
+            };
+          }
+        );
+        removeBuiltinState = pkgs.writeText "remove-builtin-state.json" (
+          builtins.toJSON { groups = [ "idm_admins" ]; }
+        );
       in
       ''
         import re
+        from datetime import datetime
 
         def assert_contains(haystack, needle):
             if needle not in haystack:
@@ -295,6 +521,27 @@
             out = provision.succeed(f"KANIDM_PASSWORD={pw} kanidm login -D idm_admin")
             assert_contains(out, "Login Success for idm_admin")
 
+        def provision_cmd(args, pw):
+            return f"KANIDM_PROVISION_IDM_ADMIN_TOKEN={pw} ${provisionExe} --url https://${serverDomain} {args} 2>&1"
+
+        def run_provision(args, pw="${provisionIdmAdminPassword}", succeed=True):
+            cmd = provision_cmd(args, pw)
+            return provision.succeed(cmd) if succeed else provision.fail(cmd)
+
+        def run_provision_error(args, pw="${provisionIdmAdminPassword}"):
+            # Errors exit with 1, --check uses 2 to report pending changes
+            status, out = provision.execute(provision_cmd(args, pw))
+            if status != 1:
+                print(out)
+                raise Exception(f"Expected exit code 1, but got {status}")
+            return out
+
+        def primary_mail(person):
+            # kanidm always lists the primary mail address first
+            out = provision.succeed(f"kanidm person get {person}")
+            match = re.search("mail: (.*)", out)
+            return match.group(1).strip() if match else None
+
         with subtest("Test Provisioning - setup"):
             provision_login(None)
             provision.succeed("kanidm logout -D idm_admin")
@@ -315,7 +562,9 @@
             provision.succeed("kanidm logout -D idm_admin")
 
         with subtest("Test Provisioning - addEntities"):
+            switch_started = int(provision.succeed("date +%s"))
             provision.succeed('${specialisations}/addEntities/bin/switch-to-configuration test')
+            switch_finished = int(provision.succeed("date +%s"))
             # Unspecified idm admin password
             provision_login(None)
 
@@ -328,6 +577,7 @@
 
             out = provision.succeed("kanidm person get testuser1")
             assert_contains(out, "name: testuser1")
+            assert_contains(out, "spn: testuser1@${serverDomain}")
             assert_contains(out, "displayname: Test User")
             assert_contains(out, "legalname: Jane Doe")
             assert_contains(out, "mail: jane.doe@example.com")
@@ -340,6 +590,13 @@
             assert_contains(out, "legalname: Ryouiki Tenkai")
             assert_contains(out, "memberof: service1-admin")
             assert_lacks(out, "mail:")
+            assert_matches(out, "account_expire: [0-9]{4}-")
+            testuser2_expiry = re.search("account_expire: (.*)", out).group(1)
+            # accountExpireIn = "90d" must expire exactly 90 days after the person was provisioned
+            expiry = int(datetime.fromisoformat(testuser2_expiry.strip().replace("Z", "+00:00")).timestamp())
+            ninety_days = 90 * 24 * 60 * 60
+            if not switch_started + ninety_days <= expiry <= switch_finished + ninety_days:
+                raise Exception(f"Expected the expiry {testuser2_expiry} to be 90 days after provisioning")
 
             out = provision.succeed("kanidm group get service1-access")
             assert_contains(out, "name: service1-access")
@@ -353,8 +610,14 @@
             assert_contains(out, "oauth2_rs_origin: https://one.example.com/")
             assert_contains(out, "oauth2_rs_origin_landing: https://one.example.com/landing")
             assert_matches(out, 'oauth2_rs_scope_map: service1-access.*{"email", "openid", "profile"}')
+            assert_matches(out, 'oauth2_rs_scope_map: service1-admin.*{"openid"}')
             assert_matches(out, 'oauth2_rs_sup_scope_map: service1-admin.*{"admin"}')
-            assert_matches(out, 'oauth2_rs_claim_map: groups:.*"admin"')
+            assert_matches(out, 'oauth2_rs_sup_scope_map: idm_all_persons.*{"directory"}')
+            assert_matches(out, 'oauth2_rs_claim_map: groups:service1-admin.*"admin"')
+            assert_matches(out, 'oauth2_rs_claim_map: groups:service1-access.*"access"')
+            assert_matches(out, 'oauth2_rs_claim_map: role:idm_all_persons.*"user"')
+            assert_matches(out, 'oauth2_rs_claim_map: role:service1-admin.*"admin"')
+            assert_matches(out, 'oauth2_rs_claim_map: is_admin:service1-admin.*"true"')
 
             out = provision.succeed("kanidm system oauth2 show-basic-secret service1")
             assert_contains(out, "very-strong-secret-for-service1")
@@ -382,10 +645,12 @@
 
             out = provision.succeed("kanidm person get testuser1")
             assert_contains(out, "name: testuser1")
+            # The spn stays the same when other attributes are provisioned again
+            assert_contains(out, "spn: testuser1@${serverDomain}")
             assert_contains(out, "displayname: Test User (changed)")
             assert_contains(out, "legalname: Jane Doe (changed)")
             assert_contains(out, "mail: jane.doe@example.com")
-            assert_contains(out, "mail: second.doe@example.com")
+            assert_contains(out, "mail: Second.Doe@Example.com")
             assert_lacks(out, "memberof: testgroup1")
             assert_contains(out, "memberof: service1-access")
 
@@ -395,6 +660,8 @@
             assert_contains(out, "legalname: Ryouiki Tenkai (changed)")
             assert_contains(out, "memberof: service1-admin")
             assert_lacks(out, "mail:")
+            # The expiry is not extended by later runs
+            assert_contains(out, f"account_expire: {testuser2_expiry}")
 
             out = provision.succeed("kanidm group get service1-access")
             assert_contains(out, "name: service1-access")
@@ -410,7 +677,12 @@
             assert_contains(out, "oauth2_rs_origin_landing: https://changed-one.example.com/landing")
             assert_matches(out, 'oauth2_rs_scope_map: service1-access.*{"email", "openid"}')
             assert_matches(out, 'oauth2_rs_sup_scope_map: service1-admin.*{"adminchanged"}')
+            # Scope maps of groups that were removed from the state are removed as orphans
+            assert_lacks(out, "oauth2_rs_scope_map: service1-admin")
+            assert_lacks(out, "oauth2_rs_sup_scope_map: idm_all_persons")
             assert_matches(out, 'oauth2_rs_claim_map: groups:.*"adminchanged"')
+            # The claim still exists, but the group that was removed from it must be gone
+            assert_lacks(out, "oauth2_rs_claim_map: groups:service1-access")
 
             out = provision.succeed("kanidm system oauth2 show-basic-secret service1")
             assert_contains(out, "changed-very-strong-secret-for-service1")
@@ -441,7 +713,7 @@
             assert_contains(out, "displayname: Test User (changed)")
             assert_lacks(out, "legalname: Jane Doe (changed)")
             assert_lacks(out, "mail: jane.doe@example.com")
-            assert_lacks(out, "mail: second.doe@example.com")
+            assert_lacks(out, "mail: Second.Doe@Example.com")
             assert_lacks(out, "memberof: testgroup1")
             assert_lacks(out, "memberof: service1-access")
 
@@ -479,8 +751,30 @@
             assert_lacks(out, "oauth2_allow_insecure_client_disable_pkce: true")
             assert_lacks(out, "oauth2_prefer_short_username: true")
 
+            out = provision.succeed("kanidm system oauth2 get service3")
+            assert_contains(out, "name: service3")
+            assert_contains(out, "oauth2_rs_origin_landing: https://three.example.com/")
+            assert_lacks(out, "oauth2_rs_origin: ")
+
+            out = provision.succeed("kanidm system oauth2 show-basic-secret service3")
+            assert_contains(out, "very-strong-secret-for-service3")
+
             provision.succeed("kanidm logout -D idm_admin")
 
+        with subtest("Test Provisioning - export"):
+            run_provision("--export /tmp/exported.json")
+            out = provision.succeed("cat /tmp/exported.json")
+            assert_contains(out, '"testuser1"')
+            assert_contains(out, '"basicSecretFile": "PLACEHOLDER')
+            # Built-in entities must never be exported, re-applying them would put them under provision tracking
+            assert_lacks(out, '"idm_admins"')
+            assert_lacks(out, '"idm_admin"')
+
+            # Re-applying the export without the placeholder secrets must not change anything
+            provision.succeed("jq 'del(.systems.oauth2[].basicSecretFile)' /tmp/exported.json > /tmp/reapply.json")
+            out = run_provision("--check --state /tmp/reapply.json")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
         with subtest("Test Provisioning - removeEntities"):
             provision.succeed('${specialisations}/removeEntities/bin/switch-to-configuration test')
             provision_login("${provisionIdmAdminPassword}")
@@ -509,6 +803,338 @@
             out = provision.succeed("kanidm system oauth2 get service2")
             assert_lacks(out, "name: service2")
 
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - builtin entities are never removed"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            out = run_provision("--no-auto-remove --state ${emptyState} --remove-state ${removeBuiltinState}", succeed=False)
+            assert_contains(out, "Refusing to remove /v1/group/idm_admins, it is a protected kanidm entity")
+
+            out = provision.succeed("kanidm group get idm_admins")
+            assert_contains(out, "name: idm_admins")
+
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - import persons from csv"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            out = run_provision("--state ${emptyState} --import-persons-csv ${personsCsv}")
+            assert_contains(out, "Imported 2 persons")
+
+            out = provision.succeed("kanidm person get csvuser1")
+            assert_contains(out, "displayname: Doe, Jane")
+            assert_contains(out, "mail: jane.doe@example.com")
+            assert_contains(out, "mail: j.doe@example.com")
+
+            out = provision.succeed("kanidm person get csvuser2")
+            assert_contains(out, 'displayname: The "Boss"')
+            assert_lacks(out, "mail:")
+
+            # Removes the imported persons as orphans
+            run_provision("--state ${emptyState}")
+            out = provision.succeed("kanidm person get csvuser1")
+            assert_lacks(out, "name: csvuser1")
+
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - posix id changes are refused"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${posixState 20000}")
+            out = provision.succeed("kanidm person get posixuser1")
+            assert_contains(out, "gidnumber: 20000")
+
+            out = run_provision("--state ${posixState 20001}", succeed=False)
+            assert_contains(out, "Refusing to change gidnumber of /v1/person/posixuser1 from 20000 to 20001")
+            out = provision.succeed("kanidm person get posixuser1")
+            assert_contains(out, "gidnumber: 20000")
+
+            run_provision("--state ${posixState 20001} --allow-id-changes")
+            out = provision.succeed("kanidm person get posixuser1")
+            assert_contains(out, "gidnumber: 20001")
+
+            # Removes posixuser1 as an orphan
+            run_provision("--state ${emptyState}")
+            out = provision.succeed("kanidm person get posixuser1")
+            assert_lacks(out, "name: posixuser1")
+
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - scope maps are compared without the domain"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${scopeMapState}")
+            out = provision.succeed("kanidm system oauth2 get scopeservice")
+            # kanidm returns the group with its domain, while the state uses the bare group name
+            assert_matches(out, 'oauth2_rs_scope_map: mygroup@${serverDomain}.*{"openid"}')
+
+            out = run_provision("--check --state ${scopeMapState}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            run_provision("--state ${emptyState}")
+            out = provision.succeed("kanidm system oauth2 get scopeservice")
+            assert_lacks(out, "name: scopeservice")
+
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - attributes of built-in accounts"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${builtinAccountState}")
+            # idm_admin is a service account, not a person
+            out = provision.succeed("kanidm service-account get idm_admin")
+            assert_contains(out, "displayname: Provisioned IDM Admin")
+            assert_contains(out, "mail: idm_admin@example.com")
+
+            # Built-in accounts are never tracked, so they can never be removed as orphans
+            out = provision.succeed("kanidm group get ext_idm_provisioned_entities")
+            assert_lacks(out, "member: idm_admin")
+
+            out = run_provision("--check --state ${builtinAccountState}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - the first mail address is the primary one"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${mailState [ "first@example.com" "second@example.com" ]}")
+            assert primary_mail("mailuser1") == "first@example.com"
+
+            # Mail addresses are ordered, so swapping them must update the primary address
+            out = run_provision("--state ${mailState [ "second@example.com" "first@example.com" ]}")
+            assert_contains(out, "/v1/person/mailuser1 mail")
+            assert primary_mail("mailuser1") == "second@example.com"
+            out = provision.succeed("kanidm person get mailuser1")
+            assert_contains(out, "mail: first@example.com")
+
+            out = run_provision("--check --state ${mailState [ "second@example.com" "first@example.com" ]}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            # An empty list removes all mail addresses
+            run_provision("--state ${mailState [ ]}")
+            assert primary_mail("mailuser1") is None
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - expired sessions are renewed during a run"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            # The hook revokes the session of the run, so the next request is rejected with 401
+            out = run_provision("--allow-hooks --state ${reauthState}")
+            assert_contains(out, "postCreate hook of group reauthgroup1")
+            assert_contains(out, "idm_admin session expired, authenticating again")
+
+            provision_login("${provisionIdmAdminPassword}")
+            out = provision.succeed("kanidm group get reauthgroup1")
+            assert_contains(out, "name: reauthgroup1")
+            out = provision.succeed("kanidm group get reauthgroup2")
+            assert_contains(out, "name: reauthgroup2")
+            out = provision.succeed("kanidm group get ext_idm_provisioned_entities")
+            assert_contains(out, "member: reauthgroup2")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - line endings of secret files are not part of the secret"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${secretFileState}")
+            out = provision.succeed("kanidm system oauth2 show-basic-secret lfservice")
+            assert_contains(out, "lf-secret-for-lfservice\n")
+            assert_lacks(out, "lf-secret-for-lfservice\n\n")
+            out = provision.succeed("kanidm system oauth2 show-basic-secret crlfservice")
+            assert_contains(out, "crlf-secret-for-crlfservice\n")
+            assert_lacks(out, "\r")
+
+            # The stored secrets match the files, so they are not updated again
+            out = run_provision("--check --state ${secretFileState}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - names with and without the domain are equivalent"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${domainSuffixState}")
+            out = provision.succeed("kanidm group get domaingroup2")
+            assert_contains(out, "member: domaingroup1@${serverDomain}")
+            out = provision.succeed("kanidm group get domaingroup3")
+            assert_contains(out, "member: domaingroup1@${serverDomain}")
+            out = provision.succeed("kanidm system oauth2 get domainservice")
+            assert_matches(out, 'oauth2_rs_scope_map: domaingroup2@${serverDomain}.*{"openid"}')
+            assert_matches(out, 'oauth2_rs_scope_map: domaingroup3@${serverDomain}.*{"email"}')
+
+            # kanidm always returns the names with the domain, which must match both forms in the state
+            out = run_provision("--check --state ${domainSuffixState}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - pkce"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${pkceState { pkce = "disable"; }}")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_contains(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            run_provision("--state ${pkceState { pkce = "require"; }}")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_lacks(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            # The legacy option still applies if pkce is left at its default
+            run_provision("--state ${
+              pkceState {
+                pkce = "default";
+                allowInsecureClientDisablePkce = true;
+              }
+            }")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_contains(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            run_provision("--state ${pkceState { pkce = "default"; }}")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_lacks(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            out = run_provision("--state ${
+              pkceState {
+                pkce = "require";
+                allowInsecureClientDisablePkce = true;
+              }
+            }", succeed=False)
+            assert_contains(out, "pkce is set to require, but allowInsecureClientDisablePkce is enabled for oauth2 resource server 'pkceservice'")
+
+            # Public clients always require pkce
+            out = run_provision("--state ${
+              pkceState {
+                pkce = "disable";
+                public = true;
+              }
+            }")
+            assert_contains(out, "ignoring disabled pkce for public client pkceservice")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_lacks(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            out = run_provision("--state ${
+              pkceState {
+                pkce = "require";
+                public = true;
+              }
+            }")
+            assert_lacks(out, "ignoring disabled pkce")
+            out = provision.succeed("kanidm system oauth2 get pkceservice")
+            assert_lacks(out, "oauth2_allow_insecure_client_disable_pkce: true")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - merge modes"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${mergeState}")
+            # Attributes that are not part of the state are added out-of-band
+            provision.succeed("kanidm person update mergeuser1 --legalname 'Out Of Band' --mail oob@example.com")
+            provision.succeed("kanidm group add-members mergegroup mergeuser2")
+
+            # merge leaves the absent attributes untouched
+            out = run_provision("--merge-mode merge --check --state ${mergeState}")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+            run_provision("--merge-mode merge --state ${mergeState}")
+            out = provision.succeed("kanidm person get mergeuser1")
+            assert_contains(out, "legalname: Out Of Band")
+            assert_contains(out, "mail: oob@example.com")
+            out = provision.succeed("kanidm group get mergegroup")
+            assert_contains(out, "member: mergeuser2")
+
+            # replace removes them
+            run_provision("--merge-mode replace --state ${mergeState}")
+            out = provision.succeed("kanidm person get mergeuser1")
+            assert_lacks(out, "legalname:")
+            assert_lacks(out, "mail:")
+            out = provision.succeed("kanidm group get mergegroup")
+            assert_lacks(out, "member: mergeuser2")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        with subtest("Test Provisioning - origins are unordered"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            run_provision("--state ${
+              originState [
+                "https://one.origin.example.com/"
+                "https://two.origin.example.com/"
+                "https://three.origin.example.com/"
+              ]
+            }")
+            out = provision.succeed("kanidm system oauth2 get originservice")
+            assert_contains(out, "oauth2_rs_origin: https://one.origin.example.com/")
+            assert_contains(out, "oauth2_rs_origin: https://two.origin.example.com/")
+            assert_contains(out, "oauth2_rs_origin: https://three.origin.example.com/")
+
+            # kanidm returns the origins in its own order, so a different order is not a change
+            out = run_provision("--check --state ${
+              originState [
+                "https://three.origin.example.com/"
+                "https://one.origin.example.com/"
+                "https://two.origin.example.com/"
+              ]
+            }")
+            assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+            run_provision("--state ${emptyState}")
+            provision.succeed("kanidm logout -D idm_admin")
+
+        # Device flow is only supported since kanidm 1.6.0
+        ${pkgs.lib.optionalString (pkgs.lib.versionAtLeast pkgs.kanidm.version "1.6.0") ''
+          with subtest("Test Provisioning - device flow"):
+              provision_login("${provisionIdmAdminPassword}")
+
+              run_provision("--state ${deviceFlowState true}")
+              out = provision.succeed("kanidm system oauth2 get deviceflowservice")
+              assert_contains(out, "oauth2_device_flow_enable: true")
+
+              run_provision("--state ${deviceFlowState false}")
+              out = provision.succeed("kanidm system oauth2 get deviceflowservice")
+              assert_lacks(out, "oauth2_device_flow_enable: true")
+              out = run_provision("--check --state ${deviceFlowState false}")
+              assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+              run_provision("--state ${deviceFlowState true}")
+              out = provision.succeed("kanidm system oauth2 get deviceflowservice")
+              assert_contains(out, "oauth2_device_flow_enable: true")
+              out = run_provision("--check --state ${deviceFlowState true}")
+              assert_contains(out, "Dry run: 0 change(s) would have been applied")
+
+              run_provision("--state ${emptyState}")
+              provision.succeed("kanidm logout -D idm_admin")
+        ''}
+
+        with subtest("Test Provisioning - invalid states are rejected"):
+            provision_login("${provisionIdmAdminPassword}")
+
+            out = run_provision_error("--state ${duplicateNameState}")
+            assert_contains(out, "1 name(s) are used by multiple entities")
+            assert_contains(out, "group: dupname")
+            assert_contains(out, "person: dupname")
+
+            out = run_provision_error("--state ${missingGroupState}")
+            assert_contains(out, "oauth2 resource server 'missinggroupservice' refers to group 'undeclaredgroup', which is not declared in the state")
+
+            out = run_provision_error("--state ${badUrlState}")
+            assert_contains(out, "Invalid originUrl 'not a url' for oauth2 resource server 'badurlservice'")
+
+            # Invalid states are rejected before anything is changed
+            out = provision.succeed("kanidm group list")
+            assert_lacks(out, "dupname")
+            out = provision.succeed("kanidm system oauth2 list")
+            assert_lacks(out, "missinggroupservice")
+            assert_lacks(out, "badurlservice")
+
             provision.succeed("kanidm logout -D idm_admin")
       '';
   }
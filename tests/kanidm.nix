@@ -425,6 +425,13 @@ in
                 default = null;
               };
 
+              accountExpireIn = lib.mkOption {
+                description = "Duration after which the account expires, derived only once while the person has no expiry.";
+                type = lib.types.nullOr lib.types.str;
+                example = "90d";
+                default = null;
+              };
+
               mailAddresses = lib.mkOption {
                 description = "Mail addresses. First given address is considered the primary address.";
                 type = lib.types.listOf lib.types.str;
@@ -451,6 +458,12 @@ in
             options = {
               present = mkPresentOption "oauth2 resource server";
 
+              enabled = lib.mkOption {
+                description = "Whether this resource server can be used. If false, it is kept with all of its settings, but its origins are removed.";
+                type = lib.types.bool;
+                default = true;
+              };
+
               public = lib.mkOption {
                 description = "Whether this is a public client (enforces PKCE, doesn't use a basic secret)";
                 type = lib.types.bool;
@@ -563,11 +576,27 @@ in
                         default = "array";
                       };
 
+                      valueType = lib.mkOption {
+                        description = "The type of the values. kanidm emits all values as strings, so this only validates their formatting.";
+                        type = lib.types.enum [
+                          "string"
+                          "boolean"
+                          "integer"
+                        ];
+                        default = "string";
+                      };
+
                       valuesByGroup = lib.mkOption {
                         description = "Maps kanidm groups to values for the claim.";
                         default = { };
                         type = lib.types.attrsOf (lib.types.listOf lib.types.str);
                       };
+
+                      defaultValues = lib.mkOption {
+                        description = "Values for all persons, in addition to the values of their groups.";
+                        default = [ ];
+                        type = lib.types.listOf lib.types.str;
+                      };
                     };
                   }
                 );
@@ -598,7 +627,15 @@ in
           opt: groups:
           let
             knownGroups = lib.attrNames (filterPresent cfg.provision.groups);
-            unknownGroups = lib.subtractLists knownGroups groups;
+            # Built-in groups like idm_all_persons can be referenced without being declared
+            isBuiltinGroup =
+              group:
+              lib.hasPrefix "idm_" group
+              || lib.elem group [
+                "system_admins"
+                "domain_admins"
+              ];
+            unknownGroups = lib.filter (x: !isBuiltinGroup x) (lib.subtractLists knownGroups groups);
           in
           {
             assertion = (cfg.enableServer && cfg.provision.enable) -> unknownGroups == [ ];
@@ -733,7 +770,7 @@ in
                 {
                   assertion =
                     (cfg.provision.enable && cfg.enableServer)
-                    -> lib.any (xs: xs != [ ]) (lib.attrValues claimCfg.valuesByGroup);
+                    -> (claimCfg.defaultValues != [ ] || lib.any (xs: xs != [ ]) (lib.attrValues claimCfg.valuesByGroup));
                   message = "services.kanidm.provision.systems.oauth2.${oauth2}.claimMaps.${claim} does not specify any values for any group";
                 }
                 # Public clients cannot define a basic secret